@@ -0,0 +1,108 @@
+//! Aborting the recording of a trace
+//!
+//! The recorder executes every instruction it records, thus the interpreter
+//! state always matches the instruction recording stopped at. Aborting only
+//! has to hand that (not yet executed) instruction back to the interpreter.
+//!
+//! Loops whose recording is aborted `Config::abort_threshold` times are
+//! blacklisted and not traced anymore (see `HotCounters::abort`).
+
+use std::fmt;
+
+use bytecode::Instruction;
+use error::VmError;
+
+
+#[derive(Debug)]
+pub enum Abort {
+    // the recorded path left the loop
+    LoopLeft,
+    // reached an inner loop, which has no trace yet
+    InnerLoop,
+    // reached an inner loop, which is blacklisted
+    BlacklistedLoop,
+    // the trace of an inner loop exited within the inner loop
+    InnerExit,
+    // traces can't execute the instruction
+    Unsupported(Instruction),
+    // the trace got longer than `Config::max_trace_length`
+    TooLong,
+    // more inlined frames than `Config::max_inline_depth`
+    TooDeep,
+    // call of a function, which is inlined already
+    Recursion(String),
+    // the instruction fails, the interpreter reports the error
+    Exception(VmError),
+    // the program ended
+    ProgramEnded,
+}
+
+impl Abort {
+    /// Whether the abort counts towards blacklisting the loop.
+    ///
+    /// Inner loops are traced before their outer loops, an outer loop
+    /// reaching an inner loop without trace just has to wait for it.
+    pub fn counts(&self) -> bool {
+        !matches!(*self, Abort::InnerLoop)
+    }
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Abort::LoopLeft => write!(f, "loop left"),
+            Abort::InnerLoop => write!(f, "inner loop has no trace"),
+            Abort::BlacklistedLoop => write!(f, "inner loop is blacklisted"),
+            Abort::InnerExit => write!(f, "inner trace exited within its loop"),
+            Abort::Unsupported(ref instr) => write!(f, "unsupported instruction {:?}", instr),
+            Abort::TooLong => write!(f, "trace too long"),
+            Abort::TooDeep => write!(f, "inlining too deep"),
+            Abort::Recursion(ref func) => write!(f, "recursive call of {}", func),
+            Abort::Exception(ref error) => write!(f, "exception: {}", error),
+            Abort::ProgramEnded => write!(f, "program ended"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testing;
+
+    /// Runs example program `name`, returns the loops whose recording was
+    /// aborted, with the number of aborts and whether they got blacklisted.
+    fn aborted_loops(name: &str, flags: &[&str]) -> Vec<(String, u32, bool)> {
+        let config = testing::config(flags);
+        let module = testing::program(name, &config);
+        let interpreter = testing::run(&module, config);
+
+        // the interpreter continued where recording stopped
+        assert!(testing::traces(&interpreter).is_empty());
+        assert_eq!(interpreter.output(), &testing::output(name, &["--no-jit"])[..]);
+
+        let counters = &interpreter.hot_counters;
+        counters.aborts()
+            .map(|(func, pc, aborts)| (String::from(func), aborts, counters.is_blacklisted(func, pc)))
+            .collect()
+    }
+
+    #[test]
+    fn loops_with_long_traces_are_blacklisted() {
+        let loops = aborted_loops("min_list", &["--max-trace-length=5", "--abort-threshold=2"]);
+        assert_eq!(loops, vec![(String::from("min_list"), 2, true)]);
+    }
+
+    #[test]
+    fn loops_with_unsupported_instructions_are_blacklisted() {
+        let loops = aborted_loops("print_loop", &["--abort-threshold=3"]);
+        assert_eq!(loops, vec![(String::from("main"), 3, true)]);
+    }
+
+    #[test]
+    fn loops_are_not_blacklisted_without_threshold() {
+        let loops = aborted_loops("print_loop", &["--abort-threshold=0"]);
+        assert_eq!(loops.len(), 1);
+        assert!(loops[0].1 > 3);
+        assert!(!loops[0].2);
+    }
+}
@@ -0,0 +1,77 @@
+//! Benchmarks
+//!
+//! Compares dispatch on `Instruction`s against the compact encoding, using
+//! the `min_list` example on a larger list. Use a release build:
+//!
+//!     cargo run --release -- bench
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+use bytecode::Instruction;
+use config::Config;
+use encoding::CompactModule;
+use programs;
+
+use super::{prepare, Interpreter, Module};
+
+
+const LIST_SIZE: usize = 1_000;
+const RUNS: u32 = 200;
+
+
+pub fn run(config: &Config) {
+    let values = (0..LIST_SIZE).map(|n| (n * 7919) % LIST_SIZE + 1).collect::<Vec<_>>();
+    let module = programs::min_list_of(&values);
+    let module = prepare(module, config);
+    let compact = CompactModule::encode(&module).expect("min_list does not fit into the compact encoding");
+
+    let instr_count = module.funcs.values().map(|func| func.instrs.len()).sum::<usize>();
+    println!("min_list, {} elements, {} runs", LIST_SIZE, RUNS);
+    println!("{:<8} {:>6} {:>10} {:>14} {:>14} {:>14} {:>14}",
+             "repr", "instrs", "bytes", "no jit", "jit", "closures", "native");
+
+    for &use_compact in &[false, true] {
+        let size = if use_compact {
+            compact.size()
+        } else {
+            instr_count * mem::size_of::<Instruction>()
+        };
+
+        let mut times = Vec::new();
+        let backends = [(false, false, false), (true, false, false), (true, true, false), (true, false, true)];
+        for &(jit, closures, native) in &backends {
+            let mut config = config.clone();
+            config.jit = jit;
+            config.closures = closures;
+            config.native = native;
+            config.compact = use_compact;
+            config.quiet = true;
+            times.push(measure(&module, &compact, &config));
+        }
+
+        println!("{:<8} {:>6} {:>10} {:>14?} {:>14?} {:>14?} {:>14?}",
+                 if use_compact { "compact" } else { "enum" },
+                 instr_count,
+                 size,
+                 times[0],
+                 times[1],
+                 times[2],
+                 times[3]);
+    }
+}
+
+/// Average time of a single run.
+fn measure(module: &Module, compact: &CompactModule, config: &Config) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        let mut interpreter = Interpreter::with_config(module, config.clone());
+        let result = if config.compact {
+            interpreter.run_compact(compact)
+        } else {
+            interpreter.run()
+        };
+        result.expect("benchmark failed");
+    }
+    start.elapsed() / RUNS
+}
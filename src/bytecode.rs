@@ -1,6 +1,6 @@
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Comp {
     Eq,
     Lt,
@@ -9,8 +9,20 @@ pub enum Comp {
     Ge,
 }
 
+impl Comp {
+    pub fn apply(self, left: usize, right: usize) -> bool {
+        match self {
+            Comp::Eq => left == right,
+            Comp::Lt => left < right,
+            Comp::Le => left <= right,
+            Comp::Gt => left > right,
+            Comp::Ge => left >= right,
+        }
+    }
+}
+
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Call(String),
     Return,
@@ -37,4 +49,49 @@ pub enum Instruction {
     Len,
     Print,
     Clone,
+
+    // superinstructions, see `superinstructions.rs`
+    // Load(a), Load(b), Cmp(how), JumpIfFalse(target)
+    CmpLocalsJumpIfFalse(usize, usize, Comp, usize),
+    // Load(idx), Const(n), Add, Store(idx)
+    IncLocal(usize, usize),
+}
+
+impl Instruction {
+    pub fn jump_target(&self) -> Option<usize> {
+        use self::Instruction::*;
+
+        match *self {
+            Jump(target) | JumpIfTrue(target) | JumpIfFalse(target) |
+            CmpLocalsJumpIfFalse(_, _, _, target) => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn with_jump_target(&self, target: usize) -> Instruction {
+        use self::Instruction::*;
+
+        match *self {
+            Jump(_) => Jump(target),
+            JumpIfTrue(_) => JumpIfTrue(target),
+            JumpIfFalse(_) => JumpIfFalse(target),
+            CmpLocalsJumpIfFalse(a, b, how, _) => CmpLocalsJumpIfFalse(a, b, how, target),
+            _ => panic!("{:?} is not a jump", self),
+        }
+    }
+}
+
+/// Follows chains of unconditional jumps, starting at `pc`. `at` returns the
+/// instruction execution continues with at a pc.
+pub fn follow_jumps<'a, F>(mut pc: usize, len: usize, at: F) -> usize
+    where F: Fn(usize) -> Option<&'a Instruction>
+{
+    // bounded by the number of instructions, since jumps might form a cycle
+    for _ in 0..len {
+        match at(pc) {
+            Some(&Instruction::Jump(target)) if target != pc => pc = target,
+            _ => break,
+        }
+    }
+    pc
 }
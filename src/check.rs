@@ -0,0 +1,90 @@
+//! Self check
+//!
+//! Runs every example program with several configurations and compares the
+//! output against the plain interpreter (no JIT, no rewriting of bytecode):
+//!
+//!     cargo run -- check
+
+use std::panic::{self, AssertUnwindSafe};
+
+use config::Config;
+use programs;
+
+use super::{prepare, Interpreter};
+
+
+const VARIANTS: &[&str] = &["",
+                            "--hot-threshold=1",
+                            "--hot-threshold=3",
+                            "--hot-threshold=10",
+                            "--no-peephole --no-superinstructions",
+                            "--compact",
+                            "--compact --hot-threshold=1",
+                            "--trace-cache-size=0",
+                            "--bridge-threshold=0",
+                            "--bridge-threshold=1",
+                            "--bridge-threshold=1 --hot-threshold=1 --compact",
+                            "--max-trace-length=5",
+                            "--max-inline-depth=0",
+                            "--abort-threshold=0",
+                            "--abort-threshold=1 --hot-threshold=1",
+                            "--passes=fold",
+                            "--passes=forward,dse",
+                            "--passes=dse --bridge-threshold=1",
+                            "--passes=peel --bridge-threshold=1 --hot-threshold=1",
+                            "--disable-pass=peel",
+                            "--disable-pass=bce",
+                            "--disable-pass=sink",
+                            "--native",
+                            "--native --bridge-threshold=1 --hot-threshold=1",
+                            "--native --no-opt",
+                            "--closures",
+                            "--closures --bridge-threshold=1 --hot-threshold=1",
+                            "--closures --native --passes=sink",
+                            "--validate",
+                            "--validate --bridge-threshold=1 --hot-threshold=1",
+                            "--validate --native --closures --compact",
+                            "--no-opt"];
+
+// the loops of the example programs only run a few times, they get hot early
+// to exercise the JIT, unless a variant sets another threshold
+const DEFAULT_FLAGS: &str = "--hot-threshold=2";
+
+
+/// Returns `false` if any configuration produced a different output.
+pub fn run() -> bool {
+    let mut ok = true;
+
+    for name in programs::NAMES {
+        let reference = output(name, "--no-jit --no-peephole --no-superinstructions");
+
+        for variant in VARIANTS {
+            let result = output(name, variant);
+            if result == reference {
+                println!("ok   {:<12} [{}]", name, variant);
+            } else {
+                println!("FAIL {:<12} [{}]: expected {:?}, got {:?}", name, variant, reference, result);
+                ok = false;
+            }
+        }
+    }
+
+    ok
+}
+
+fn output(name: &str, flags: &str) -> Result<Vec<usize>, String> {
+    let flags = DEFAULT_FLAGS.split_whitespace().chain(flags.split_whitespace());
+    let mut config = Config::parse(flags.map(String::from))?;
+    config.quiet = true;
+
+    let module = prepare(programs::get(name).unwrap(), &config);
+
+    panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut interpreter = Interpreter::with_config(&module, config.clone());
+            match interpreter.run_program() {
+                Ok(()) => Ok(interpreter.output().to_vec()),
+                Err(error) => Err(format!("{} after {:?}", error, interpreter.output())),
+            }
+        }))
+        .unwrap_or_else(|_| Err(String::from("panicked")))
+}
@@ -0,0 +1,243 @@
+//! Closure compiled traces
+//!
+//! With `--closures`, every instruction of a trace is translated into a
+//! closure once, with its registers, slots and constants already resolved
+//! (see `regalloc.rs`). Executing the trace calls one closure after the
+//! other, instead of decoding every `Ir` again in each iteration. Like the
+//! runner, they keep numbers and bools unboxed (see `tracerunner::Slots`).
+//!
+//! The closures only compute values. Guards report whether they failed,
+//! calls of inner loops are left to the runner (`Flow`), which handles both
+//! exactly like for interpreted traces.
+
+use std::fmt;
+use std::mem;
+
+use ir::{self, Ir, Repr};
+use repr::{Type, Value};
+use tracerunner::{Registers, Slots};
+
+use super::Trace;
+
+
+/// What the runner continues with after a closure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Next,
+    // the guard (or exit) failed
+    Exit,
+    // the inner loop has to be run
+    CallLoop,
+}
+
+/// Everything the closures of a trace work on.
+pub struct State<'s> {
+    pub values: &'s mut Registers,
+    // locals of all running traces, the ones of this trace start at `base`
+    pub slots: &'s mut Slots,
+    pub base: usize,
+    // operand stack at the start of a bridge
+    pub entry: &'s [Value],
+}
+
+type Op = Box<dyn Fn(&mut State) -> Flow>;
+
+pub struct Closures {
+    // one per instruction of the trace
+    pub ops: Vec<Op>,
+}
+
+impl Closures {
+    /// Approximate number of bytes, without what the closures captured.
+    pub fn size(&self) -> usize {
+        self.ops.len() * mem::size_of::<Op>()
+    }
+}
+
+impl fmt::Debug for Closures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Closures({} ops)", self.ops.len())
+    }
+}
+
+pub fn compile(trace: &Trace) -> Closures {
+    let ops = trace.trace
+        .iter()
+        .enumerate()
+        .map(|(pc, op)| compile_op(trace, pc, op))
+        .collect();
+
+    Closures { ops }
+}
+
+fn compile_op(trace: &Trace, pc: usize, op: &Ir) -> Op {
+    let regs = &trace.registers;
+    let dst = regs[pc];
+
+    match *op {
+        Ir::Const(n) => Box::new(move |s| {
+            s.values.raw[dst] = n;
+            Flow::Next
+        }),
+        Ir::ConstBool(b) => Box::new(move |s| {
+            s.values.raw[dst] = b as usize;
+            Flow::Next
+        }),
+        Ir::Load(slot) => {
+            let read_only = trace.read_only[slot];
+            Box::new(move |s| {
+                s.slots.load(s.base + slot, s.values, dst, read_only);
+                Flow::Next
+            })
+        }
+        Ir::Store(slot, val) => compile_store(trace, slot, val),
+
+        Ir::Add(a, b) => {
+            let (a, b) = (regs[a], regs[b]);
+            Box::new(move |s| {
+                s.values.raw[dst] = s.values.raw[a] + s.values.raw[b];
+                Flow::Next
+            })
+        }
+        Ir::Overflows(a, b) => {
+            let (a, b) = (regs[a], regs[b]);
+            Box::new(move |s| {
+                s.values.raw[dst] = s.values.raw[a].checked_add(s.values.raw[b]).is_none() as usize;
+                Flow::Next
+            })
+        }
+        Ir::Cmp(how, left, right) => {
+            let (left, right) = (regs[left], regs[right]);
+            Box::new(move |s| {
+                s.values.raw[dst] = how.apply(s.values.raw[left], s.values.raw[right]) as usize;
+                Flow::Next
+            })
+        }
+        Ir::ArrayGet(xs, index) => {
+            let (xs, index) = (regs[xs], regs[index]);
+            Box::new(move |s| {
+                s.values.raw[dst] = s.values.array(xs, s.slots)[s.values.raw[index]];
+                Flow::Next
+            })
+        }
+        Ir::Len(xs) => {
+            let xs = regs[xs];
+            Box::new(move |s| {
+                s.values.raw[dst] = s.values.array(xs, s.slots).len();
+                Flow::Next
+            })
+        }
+        Ir::NewArray(capacity) => Box::new(move |s| {
+            s.values.set(dst, Value::Array(Vec::with_capacity(capacity)));
+            Flow::Next
+        }),
+        Ir::Push(xs, val) => {
+            let (xs, val) = (regs[xs], regs[val]);
+            Box::new(move |s| {
+                let mut array = s.values.array(xs, s.slots).to_vec();
+                array.push(s.values.raw[val]);
+                s.values.set(dst, Value::Array(array));
+                Flow::Next
+            })
+        }
+        Ir::Entry(idx) => Box::new(move |s| {
+            s.values.load(dst, &s.entry[idx]);
+            Flow::Next
+        }),
+        // see `Registers::value`
+        Ir::Sunk(_) |
+        Ir::Nop => Box::new(|_| Flow::Next),
+
+        Ir::Guard(cond, ref guard, _) => {
+            let (cond, expected) = (regs[cond], guard.condition);
+            Box::new(move |s| {
+                if (s.values.raw[cond] != 0) != expected {
+                    Flow::Exit
+                } else {
+                    Flow::Next
+                }
+            })
+        }
+        Ir::Exit(..) => Box::new(|_| Flow::Exit),
+        Ir::CallLoop(..) => Box::new(|_| Flow::CallLoop),
+    }
+}
+
+/// Numbers and bools are stored unboxed, how is known from the stored
+/// instruction.
+fn compile_store(trace: &Trace, slot: usize, val: ir::Ref) -> Op {
+    let regs = &trace.registers;
+    let reg = regs[val];
+
+    match ir::repr(&trace.trace[val]) {
+        Repr::Usize => Box::new(move |s| {
+            s.slots.store_raw(s.base + slot, Type::Usize, s.values.raw[reg]);
+            Flow::Next
+        }),
+        Repr::Bool => Box::new(move |s| {
+            s.slots.store_raw(s.base + slot, Type::Bool, s.values.raw[reg]);
+            Flow::Next
+        }),
+        Repr::Boxed => Box::new(move |s| {
+            s.slots.store_register(s.base + slot, s.values, reg);
+            Flow::Next
+        }),
+        Repr::Sunk(elements) => {
+            let elements = elements.iter().map(|element| regs[*element]).collect::<Vec<_>>();
+            Box::new(move |s| {
+                let array = Value::Array(elements.iter().map(|reg| s.values.raw[*reg]).collect());
+                s.slots.store_array(s.base + slot, array);
+                Flow::Next
+            })
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use programs;
+    use repr::Value;
+    use testing;
+
+    fn usizes(values: &[usize]) -> Vec<Value> {
+        values.iter().map(|n| Value::Usize(*n)).collect()
+    }
+
+    #[test]
+    fn every_instruction_is_a_closure() {
+        let (_, _, trace) = testing::trace_of("min_list", &["--closures"]);
+        let closures = trace.closures.as_ref().unwrap();
+        assert_eq!(closures.ops.len(), trace.trace.len());
+    }
+
+    #[test]
+    fn exits_are_the_same_as_in_the_runner() {
+        let min_list = |xs: &[usize]| {
+            vec![Value::Array(xs.to_vec()), Value::Usize(xs[0]), Value::Usize(xs.len()), Value::Usize(0)]
+        };
+        // a failing guard, the loop condition and sunk arrays
+        let entries = [("min_list", min_list(&[9, 3, 4, 1])),
+                       ("min_list", min_list(&[9, 8, 7, 6])),
+                       ("array_sink", usizes(&[0, 0])),
+                       ("sum", usizes(&[0, 3]))];
+
+        for &(name, ref entry) in &entries {
+            let closures = testing::trace_exit(name, &["--closures"], entry);
+            let runner = testing::trace_exit(name, &[], entry);
+            assert_eq!(closures, runner, "{} {:?}", name, entry);
+        }
+    }
+
+    #[test]
+    fn output_is_the_same_as_without_jit() {
+        // the others stop with an error
+        let names = programs::NAMES.iter().filter(|&&name| name != "out_of_bounds" && name != "overflow");
+
+        for name in names {
+            let expected = testing::output(name, &["--no-jit"]);
+            assert_eq!(testing::output(name, &["--closures"]), expected, "{}", name);
+            assert_eq!(testing::output(name, &["--closures", "--bridge-threshold=1"]), expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Runtime configuration of the VM
+//!
+//! Everything in here can be changed per run from the command line, e.g.:
+//!
+//!     cargo run -- --no-jit --compact
+
+use std::env;
+use std::str::FromStr;
+
+use opt;
+
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // record and execute traces for loops
+    pub jit: bool,
+    // number of executions of a loop header until the loop is traced
+    pub hot_threshold: u32,
+    // loop counters are halved after this many loop header executions,
+    // 0 disables decay
+    pub hot_decay: u32,
+    // maximum number of bytes used by cached traces
+    pub trace_cache_size: usize,
+    // number of failures until a guard gets a side trace, 0 disables them
+    pub bridge_threshold: u32,
+    // recording is aborted, if the trace gets longer
+    pub max_trace_length: usize,
+    // recording is aborted, if more frames have to be inlined
+    pub max_inline_depth: usize,
+    // number of aborted recordings until a loop is blacklisted, 0 disables
+    // blacklisting
+    pub abort_threshold: u32,
+    // trace optimization passes, in the order they run
+    pub passes: Vec<String>,
+    // passes which don't run, even if listed in `passes`
+    pub disabled_passes: Vec<String>,
+    // dispatch on the compact encoding instead of `Instruction`s
+    pub compact: bool,
+    // compile traces to machine code, if supported (see `native`)
+    pub native: bool,
+    // run traces as chains of closures instead of interpreting them (see
+    // `closures`)
+    pub closures: bool,
+    // compare the state after every trace against an interpreter without
+    // JIT (see `validate`)
+    pub validate: bool,
+    // run the peephole optimizer over all functions before execution
+    pub peephole: bool,
+    // fuse common instruction sequences into superinstructions
+    pub superinstructions: bool,
+    // name of the example program to run
+    pub program: String,
+    // don't write output of `Print` to stdout
+    pub quiet: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            jit: true,
+            hot_threshold: 50,
+            hot_decay: 1000,
+            trace_cache_size: 64 * 1024,
+            bridge_threshold: 3,
+            max_trace_length: 1000,
+            max_inline_depth: 10,
+            abort_threshold: 3,
+            passes: opt::NAMES.iter().map(|name| String::from(*name)).collect(),
+            disabled_passes: Vec::new(),
+            compact: false,
+            native: false,
+            closures: false,
+            validate: false,
+            peephole: true,
+            superinstructions: true,
+            program: String::from("min_list"),
+            quiet: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self, String> {
+        Config::parse(env::args().skip(1))
+    }
+
+    /// Parses flags (`--flag` or `--option=value`), positional arguments are
+    /// ignored.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut config = Config::default();
+
+        for arg in args {
+            match arg.as_str() {
+                "--jit" => config.jit = true,
+                "--no-jit" => config.jit = false,
+                "--compact" => config.compact = true,
+                "--native" => config.native = true,
+                "--no-native" => config.native = false,
+                "--closures" => config.closures = true,
+                "--no-closures" => config.closures = false,
+                "--validate" => config.validate = true,
+                "--peephole" => config.peephole = true,
+                "--no-peephole" => config.peephole = false,
+                "--superinstructions" => config.superinstructions = true,
+                "--no-superinstructions" => config.superinstructions = false,
+                "--quiet" => config.quiet = true,
+                "--no-opt" => config.passes.clear(),
+
+                option if option.starts_with("--program=") => {
+                    config.program = value(option)?;
+                }
+                option if option.starts_with("--hot-threshold=") => {
+                    config.hot_threshold = value(option)?;
+                }
+                option if option.starts_with("--hot-decay=") => {
+                    config.hot_decay = value(option)?;
+                }
+                option if option.starts_with("--trace-cache-size=") => {
+                    config.trace_cache_size = value(option)?;
+                }
+                option if option.starts_with("--bridge-threshold=") => {
+                    config.bridge_threshold = value(option)?;
+                }
+                option if option.starts_with("--max-trace-length=") => {
+                    config.max_trace_length = value(option)?;
+                }
+                option if option.starts_with("--max-inline-depth=") => {
+                    config.max_inline_depth = value(option)?;
+                }
+                option if option.starts_with("--abort-threshold=") => {
+                    config.abort_threshold = value(option)?;
+                }
+                option if option.starts_with("--passes=") => {
+                    let passes: String = value(option)?;
+                    config.passes = passes.split(',')
+                        .filter(|name| !name.is_empty())
+                        .map(pass_name)
+                        .collect::<Result<_, _>>()?;
+                }
+                option if option.starts_with("--disable-pass=") => {
+                    let name: String = value(option)?;
+                    config.disabled_passes.push(pass_name(&name)?);
+                }
+
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown flag {:?}", flag));
+                }
+
+                _ => (),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn pass_name(name: &str) -> Result<String, String> {
+    if opt::NAMES.contains(&name) {
+        Ok(name.into())
+    } else {
+        Err(format!("unknown pass {:?}, available: {}", name, opt::NAMES.join(",")))
+    }
+}
+
+fn value<T: FromStr>(option: &str) -> Result<T, String> {
+    let value = &option[option.find('=').unwrap() + 1..];
+    value.parse().map_err(|_| format!("invalid value for {:?}", option))
+}
@@ -52,6 +52,29 @@ impl From<Value> for usize {
     }
 }
 
+impl Value {
+    pub fn as_usize(&self) -> usize {
+        match *self {
+            Value::Usize(n) => n,
+            _ => panic!("unexpected {:?}, expected usize", self),
+        }
+    }
+
+    pub fn as_bool(&self) -> bool {
+        match *self {
+            Value::Bool(b) => b,
+            _ => panic!("unexpected {:?}, expected bool", self),
+        }
+    }
+
+    pub fn as_array(&self) -> &[usize] {
+        match *self {
+            Value::Array(ref xs) => xs,
+            _ => panic!("unexpected {:?}, expected array", self),
+        }
+    }
+}
+
 impl From<usize> for Value {
     fn from(n: usize) -> Self {
         Value::Usize(n)
@@ -59,7 +82,7 @@ impl From<usize> for Value {
 }
 
 
-impl<'a> From<&'a Instruction> for TraceInstruction {
+impl From<&Instruction> for TraceInstruction {
     fn from(instr: &Instruction) -> TraceInstruction {
         use Instruction as I;
         use TraceInstruction as TI;
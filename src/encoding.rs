@@ -0,0 +1,541 @@
+//! Compact encoding of bytecode
+//!
+//! `Instruction` is an enum, which makes every instruction as large as its
+//! largest variant (`Call` carries a `String`). The compact encoding stores
+//! each instruction as a one byte opcode followed by its operands inline:
+//!
+//!     op          operands
+//!     Call        u16 function index
+//!     Cmp         u8 comparison
+//!     Jump*       u32 byte offset of target
+//!     Load/Store  u16 local index
+//!     Const       u32 value
+//!     Array       u32 capacity
+//!     CmpLocalsJumpIfFalse
+//!                 u16 local, u16 local, u8 comparison, u32 byte offset
+//!     IncLocal    u16 local, u32 value
+//!
+//! All other opcodes have no operands. Multi byte operands are little endian.
+//! Modules with larger operands can't be encoded, `Interpreter::run_program`
+//! executes their `Instruction`s instead.
+//!
+//! Every `Instruction` has its own opcode (`Op::of`), matches on `Op` have
+//! no wildcard. Thus the dispatch loop of `run_compact` handles every
+//! instruction `Interpreter::step` does, using the same `do_*` helpers.
+//!
+//! Tracing still operates on `Instruction`s, therefore every `CompactFunc`
+//! keeps a reference to the function it was encoded from, together with the
+//! byte offset of every instruction.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use bytecode::{Comp, Instruction};
+use error::VmError;
+use repr::{CallFrame, Func, InstrPtr};
+use traits::vec::ConvertingStack;
+
+use super::{Interpreter, Module};
+
+
+/// Opcode of the compact encoding, its byte is the index in `OPS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Call,
+    Return,
+    Add,
+    Cmp,
+    Jump,
+    JumpIfTrue,
+    JumpIfFalse,
+    Load,
+    Store,
+    Const,
+    Array,
+    ArrayGet,
+    Push,
+    Loop,
+    Break,
+    Len,
+    Print,
+    Clone,
+    CmpLocalsJumpIfFalse,
+    IncLocal,
+}
+
+// opcode byte -> `Op`
+const OPS: [Op; 20] = [Op::Call,
+                       Op::Return,
+                       Op::Add,
+                       Op::Cmp,
+                       Op::Jump,
+                       Op::JumpIfTrue,
+                       Op::JumpIfFalse,
+                       Op::Load,
+                       Op::Store,
+                       Op::Const,
+                       Op::Array,
+                       Op::ArrayGet,
+                       Op::Push,
+                       Op::Loop,
+                       Op::Break,
+                       Op::Len,
+                       Op::Print,
+                       Op::Clone,
+                       Op::CmpLocalsJumpIfFalse,
+                       Op::IncLocal];
+
+impl Op {
+    pub fn of(instr: &Instruction) -> Op {
+        use bytecode::Instruction::*;
+
+        match *instr {
+            Call(_) => Op::Call,
+            Return => Op::Return,
+            Add => Op::Add,
+            Cmp(_) => Op::Cmp,
+            Jump(_) => Op::Jump,
+            JumpIfTrue(_) => Op::JumpIfTrue,
+            JumpIfFalse(_) => Op::JumpIfFalse,
+            Load(_) => Op::Load,
+            Store(_) => Op::Store,
+            Const(_) => Op::Const,
+            Array(_) => Op::Array,
+            ArrayGet => Op::ArrayGet,
+            Push => Op::Push,
+            Loop => Op::Loop,
+            Break => Op::Break,
+            Len => Op::Len,
+            Print => Op::Print,
+            Clone => Op::Clone,
+            CmpLocalsJumpIfFalse(..) => Op::CmpLocalsJumpIfFalse,
+            IncLocal(..) => Op::IncLocal,
+        }
+    }
+
+    /// Opcode of `byte`, which fails for bytes of corrupt code.
+    #[inline(always)]
+    pub fn decode(byte: u8) -> Result<Op, VmError> {
+        OPS.get(byte as usize).cloned().ok_or(VmError::InvalidOpcode(byte))
+    }
+}
+
+
+pub struct CompactFunc {
+    // function this code was encoded from
+    pub func: Rc<Func>,
+    pub code: Vec<u8>,
+    // byte offset of every instruction, indexed by pc
+    pub offsets: Vec<usize>,
+}
+
+pub struct CompactModule {
+    pub funcs: Vec<CompactFunc>,
+    pub index: BTreeMap<String, usize>,
+}
+
+
+impl CompactModule {
+    /// Encodes all functions of `module`, fails if an operand does not fit.
+    pub fn encode(module: &Module) -> Result<Self, String> {
+        let index = module.funcs
+            .keys()
+            .enumerate()
+            .map(|(idx, name)| (name.clone(), idx))
+            .collect();
+
+        let funcs = module.funcs
+            .values()
+            .map(|func| CompactFunc::encode(func.clone(), &index))
+            .collect::<Result<_, _>>()?;
+
+        Ok(CompactModule {
+            funcs,
+            index,
+        })
+    }
+
+    pub fn decode(&self) -> Module {
+        Module {
+            funcs: self.funcs
+                .iter()
+                .map(|func| (func.func.name.clone(), Rc::new(func.decode(self))))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> &CompactFunc {
+        &self.funcs[self.index[name]]
+    }
+
+    /// Number of bytes used by all instructions.
+    pub fn size(&self) -> usize {
+        self.funcs.iter().map(|func| func.code.len()).sum()
+    }
+}
+
+
+impl CompactFunc {
+    pub fn encode(func: Rc<Func>, index: &BTreeMap<String, usize>) -> Result<Self, String> {
+        use bytecode::Instruction::*;
+
+        // offsets are needed upfront to resolve jump targets
+        let mut offsets = Vec::with_capacity(func.instrs.len() + 1);
+        let mut offset = 0;
+        for instr in &func.instrs {
+            offsets.push(offset);
+            offset += encoded_size(instr);
+        }
+        offsets.push(offset);
+
+        let mut code = Vec::with_capacity(offset);
+        for instr in &func.instrs {
+            code.push(Op::of(instr) as u8);
+
+            match *instr {
+                Call(ref target) => emit_u16(&mut code, index[target])?,
+                Cmp(how) => code.push(how as u8),
+                Jump(target) | JumpIfTrue(target) | JumpIfFalse(target) => emit_u32(&mut code, offsets[target])?,
+                Load(idx) | Store(idx) => emit_u16(&mut code, idx)?,
+                Const(n) | Array(n) => emit_u32(&mut code, n)?,
+                CmpLocalsJumpIfFalse(a, b, how, target) => {
+                    emit_u16(&mut code, a)?;
+                    emit_u16(&mut code, b)?;
+                    code.push(how as u8);
+                    emit_u32(&mut code, offsets[target])?;
+                }
+                IncLocal(idx, n) => {
+                    emit_u16(&mut code, idx)?;
+                    emit_u32(&mut code, n)?;
+                }
+                Return | Add | ArrayGet | Push | Loop | Break | Len | Print | Clone => (),
+            }
+        }
+
+        Ok(CompactFunc {
+            func,
+            code,
+            offsets,
+        })
+    }
+
+    pub fn decode(&self, module: &CompactModule) -> Func {
+        use bytecode::Instruction::*;
+
+        let pcs: BTreeMap<usize, usize> = self.offsets
+            .iter()
+            .enumerate()
+            .map(|(pc, offset)| (*offset, pc))
+            .collect();
+
+        let mut instrs = Vec::with_capacity(self.offsets.len() - 1);
+        let mut ip = 0;
+        while ip < self.code.len() {
+            let op = Op::decode(self.code[ip]).unwrap_or_else(|error| panic!("{}", error));
+            let instr = match op {
+                Op::Call => Call(module.funcs[read_u16(&self.code, ip + 1)].func.name.clone()),
+                Op::Return => Return,
+                Op::Add => Add,
+                Op::Cmp => Cmp(comp(self.code[ip + 1])),
+                Op::Jump => Jump(pcs[&read_u32(&self.code, ip + 1)]),
+                Op::JumpIfTrue => JumpIfTrue(pcs[&read_u32(&self.code, ip + 1)]),
+                Op::JumpIfFalse => JumpIfFalse(pcs[&read_u32(&self.code, ip + 1)]),
+                Op::Load => Load(read_u16(&self.code, ip + 1)),
+                Op::Store => Store(read_u16(&self.code, ip + 1)),
+                Op::Const => Const(read_u32(&self.code, ip + 1)),
+                Op::Array => Array(read_u32(&self.code, ip + 1)),
+                Op::ArrayGet => ArrayGet,
+                Op::Push => Push,
+                Op::Loop => Loop,
+                Op::Break => Break,
+                Op::Len => Len,
+                Op::Print => Print,
+                Op::Clone => Clone,
+                Op::CmpLocalsJumpIfFalse => {
+                    CmpLocalsJumpIfFalse(read_u16(&self.code, ip + 1),
+                                         read_u16(&self.code, ip + 3),
+                                         comp(self.code[ip + 5]),
+                                         pcs[&read_u32(&self.code, ip + 6)])
+                }
+                Op::IncLocal => IncLocal(read_u16(&self.code, ip + 1), read_u32(&self.code, ip + 3)),
+            };
+
+            ip += encoded_size(&instr);
+            instrs.push(instr);
+        }
+
+        Func {
+            name: self.func.name.clone(),
+            args_count: self.func.args_count,
+            locals_count: self.func.locals_count,
+            instrs,
+        }
+    }
+
+    /// Maps a byte offset back to the pc of the `Instruction`.
+    pub fn pc(&self, offset: usize) -> usize {
+        self.offsets
+            .binary_search(&offset)
+            .expect("offset does not point to an instruction")
+    }
+}
+
+
+fn encoded_size(instr: &Instruction) -> usize {
+    use bytecode::Instruction::*;
+
+    match *instr {
+        Call(_) | Load(_) | Store(_) => 3,
+        Cmp(_) => 2,
+        Jump(_) | JumpIfTrue(_) | JumpIfFalse(_) | Const(_) | Array(_) => 5,
+        CmpLocalsJumpIfFalse(..) => 10,
+        IncLocal(..) => 7,
+        _ => 1,
+    }
+}
+
+fn comp(byte: u8) -> Comp {
+    match byte {
+        0 => Comp::Eq,
+        1 => Comp::Lt,
+        2 => Comp::Le,
+        3 => Comp::Gt,
+        4 => Comp::Ge,
+        _ => panic!("invalid comparison {:#x}", byte),
+    }
+}
+
+fn emit_u16(code: &mut Vec<u8>, n: usize) -> Result<(), String> {
+    if n > 0xffff {
+        return Err(format!("operand {} does not fit into u16", n));
+    }
+    code.push(n as u8);
+    code.push((n >> 8) as u8);
+    Ok(())
+}
+
+fn emit_u32(code: &mut Vec<u8>, n: usize) -> Result<(), String> {
+    if n > 0xffff_ffff {
+        return Err(format!("operand {} does not fit into u32", n));
+    }
+    for shift in &[0, 8, 16, 24] {
+        code.push((n >> shift) as u8);
+    }
+    Ok(())
+}
+
+#[inline(always)]
+fn read_u16(code: &[u8], at: usize) -> usize {
+    code[at] as usize | (code[at + 1] as usize) << 8
+}
+
+#[inline(always)]
+fn read_u32(code: &[u8], at: usize) -> usize {
+    code[at] as usize | (code[at + 1] as usize) << 8 | (code[at + 2] as usize) << 16 |
+    (code[at + 3] as usize) << 24
+}
+
+
+impl<'a> Interpreter<'a> {
+    /// Same as `run`, but dispatches directly on the compact encoding.
+    ///
+    /// Loops are handed over to the tracing machinery, which works on
+    /// `Instruction`s. Afterwards execution continues with the compact code.
+    pub fn run_compact(&mut self, module: &CompactModule) -> Result<(), VmError> {
+        let main = module.get("main");
+        self.frames.push(CallFrame::for_fn(&main.func, InstrPtr::new(main.func.clone(), 0)));
+
+        let mut func = main;
+        let mut ip = 0;
+
+        loop {
+            let code = &func.code;
+            let op = Op::decode(code[ip])?;
+
+            info!("E: {:?} @{:}[{:}]", op, func.func.name, ip);
+
+            match op {
+                Op::Break | Op::Clone => ip += 1,
+
+                Op::Const => {
+                    self.do_const(read_u32(code, ip + 1));
+                    ip += 5;
+                }
+                Op::Add => {
+                    self.do_add()?;
+                    ip += 1;
+                }
+                Op::Load => {
+                    self.do_load(read_u16(code, ip + 1));
+                    ip += 3;
+                }
+                Op::Store => {
+                    self.do_store(read_u16(code, ip + 1));
+                    ip += 3;
+                }
+                Op::Print => {
+                    self.do_print();
+                    ip += 1;
+                }
+                Op::Array => {
+                    self.do_array(read_u32(code, ip + 1));
+                    ip += 5;
+                }
+                Op::Len => {
+                    self.do_len();
+                    ip += 1;
+                }
+                Op::Push => {
+                    self.do_push();
+                    ip += 1;
+                }
+                Op::ArrayGet => {
+                    self.do_array_get()?;
+                    ip += 1;
+                }
+                Op::Cmp => {
+                    self.do_cmp(comp(code[ip + 1]));
+                    ip += 2;
+                }
+
+                Op::Loop => {
+                    let pc = func.pc(ip);
+                    let next = match self.enter_loop(&InstrPtr::new(func.func.clone(), pc))? {
+                        Some(next) => next,
+                        None => break,
+                    };
+
+                    func = module.get(&next.func.name);
+                    ip = func.offsets[next.pc];
+                }
+
+                Op::Call => {
+                    let target = &module.funcs[read_u16(code, ip + 1)];
+                    let back_ref = InstrPtr::new(func.func.clone(), func.pc(ip) + 1);
+                    self.do_call(&target.func, back_ref);
+                    func = target;
+                    ip = 0;
+                }
+
+                Op::Return => {
+                    let back_ref = match self.do_return() {
+                        Some(back_ref) => back_ref,
+                        None => break,
+                    };
+
+                    func = module.get(&back_ref.func.name);
+                    ip = func.offsets[back_ref.pc];
+                }
+
+                Op::Jump => ip = read_u32(code, ip + 1),
+
+                Op::JumpIfTrue => {
+                    if self.stack.pop_into::<bool>() {
+                        ip = read_u32(code, ip + 1);
+                    } else {
+                        ip += 5;
+                    }
+                }
+
+                Op::JumpIfFalse => {
+                    if !self.stack.pop_into::<bool>() {
+                        ip = read_u32(code, ip + 1);
+                    } else {
+                        ip += 5;
+                    }
+                }
+
+                Op::CmpLocalsJumpIfFalse => {
+                    let how = comp(code[ip + 5]);
+                    if !self.do_cmp_locals(read_u16(code, ip + 1), read_u16(code, ip + 3), how) {
+                        ip = read_u32(code, ip + 6);
+                    } else {
+                        ip += 10;
+                    }
+                }
+
+                Op::IncLocal => {
+                    self.do_inc_local(read_u16(code, ip + 1), read_u32(code, ip + 3))?;
+                    ip += 7;
+                }
+            }
+        }
+
+        self.log_statistics();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytecode::Instruction::*;
+    use config::Config;
+    use programs;
+    use repr::Func;
+
+    use super::super::{prepare, Interpreter, Module};
+    use super::{CompactModule, Op, OPS};
+
+    /// `main` printing `a + b`.
+    fn print_sum(a: usize, b: usize) -> Module {
+        Module {
+            funcs: btreemap!{
+                "main".into() => Func {
+                    name: "main".into(),
+                    args_count: 0,
+                    locals_count: 0,
+                    instrs: vec![Const(a), Const(b), Add, Print, Return],
+                }.into(),
+            },
+        }
+    }
+
+    #[test]
+    fn roundtrip_programs() {
+        for name in programs::NAMES {
+            // with and without superinstructions, which have opcodes of their own
+            let plain = programs::get(name).unwrap();
+            let prepared = prepare(programs::get(name).unwrap(), &Config::default());
+
+            for module in &[plain, prepared] {
+                let decoded = CompactModule::encode(module).unwrap().decode();
+                assert_eq!(decoded.funcs.len(), module.funcs.len());
+                for (func_name, func) in &module.funcs {
+                    let other = &decoded.funcs[func_name];
+                    assert_eq!(other.instrs, func.instrs, "{} in {}", func_name, name);
+                    assert_eq!(other.args_count, func.args_count);
+                    assert_eq!(other.locals_count, func.locals_count);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_operands_are_not_encoded() {
+        assert!(CompactModule::encode(&print_sum(1, 2)).is_ok());
+        assert!(CompactModule::encode(&print_sum(1 << 32, 2)).is_err());
+    }
+
+    #[test]
+    fn modules_with_large_operands_run_uncompacted() {
+        // folding creates a constant, which does not fit into u32
+        let mut config = Config::parse(vec![String::from("--compact")]).unwrap();
+        config.quiet = true;
+        let module = prepare(print_sum(1 << 31, 1 << 31), &config);
+        assert_eq!(module.funcs["main"].instrs[0], Const(1 << 32));
+
+        let mut interpreter = Interpreter::with_config(&module, config);
+        interpreter.run_program().unwrap();
+        assert_eq!(interpreter.output(), &[1 << 32]);
+    }
+
+    #[test]
+    fn opcodes_are_their_index() {
+        for (byte, op) in OPS.iter().enumerate() {
+            assert_eq!(*op as usize, byte);
+            assert_eq!(Op::decode(byte as u8), Ok(*op));
+        }
+        assert!(Op::decode(OPS.len() as u8).is_err());
+    }
+}
@@ -0,0 +1,27 @@
+//! Runtime errors of the VM
+//!
+//! Only the interpreter raises errors. Traces check the operations which
+//! might fail with guards and leave the trace before the failing instruction,
+//! the interpreter then executes it again and reports the error.
+
+use std::fmt;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    // result of an addition does not fit into `usize`
+    Overflow,
+    IndexOutOfBounds { index: usize, len: usize },
+    // byte of compact code, which is no opcode (see `encoding.rs`)
+    InvalidOpcode(u8),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::Overflow => write!(f, "overflow"),
+            VmError::IndexOutOfBounds { index, len } => write!(f, "index {} out of bounds (len {})", index, len),
+            VmError::InvalidOpcode(byte) => write!(f, "invalid opcode {:#x}", byte),
+        }
+    }
+}
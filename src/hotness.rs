@@ -0,0 +1,190 @@
+//! Hot loop detection
+//!
+//! Recording a trace is expensive, thus only loops which are executed often
+//! enough are traced. Every execution of a loop header (the `Loop`
+//! instruction is the target of the back-edge) increments its counter. Once a
+//! counter reaches the threshold, the loop is considered hot.
+//!
+//! Counters decay: after `decay_interval` ticks in total, all counters are
+//! halved. Loops which were warm a long time ago therefore don't become hot
+//! by accident much later on.
+//!
+//! Loops whose recording was aborted too often are blacklisted, they never
+//! become hot again. So are loops whose trace does not fit into the trace
+//! cache, recording them again would give a trace of the same size.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+
+pub struct HotCounters {
+    threshold: u32,
+    decay_interval: u32,
+    ticks: u32,
+    // function name -> pc of `Loop` instruction -> count
+    counters: BTreeMap<String, BTreeMap<usize, u32>>,
+    abort_threshold: u32,
+    // function name -> pc of `Loop` instruction -> aborted recordings
+    aborts: BTreeMap<String, BTreeMap<usize, u32>>,
+    // function name -> pcs of loops blacklisted regardless of aborts
+    blacklist: BTreeMap<String, BTreeSet<usize>>,
+}
+
+impl HotCounters {
+    /// A `decay_interval` of 0 disables decay, an `abort_threshold` of 0
+    /// disables blacklisting.
+    pub fn new(threshold: u32, decay_interval: u32, abort_threshold: u32) -> Self {
+        HotCounters {
+            threshold,
+            decay_interval,
+            ticks: 0,
+            counters: BTreeMap::new(),
+            abort_threshold,
+            aborts: BTreeMap::new(),
+            blacklist: BTreeMap::new(),
+        }
+    }
+
+    /// Counts an execution of the loop header, returns `true` if the loop is
+    /// hot. Blacklisted loops are never hot.
+    pub fn tick(&mut self, func: &str, pc: usize) -> bool {
+        if self.is_blacklisted(func, pc) {
+            return false;
+        }
+
+        self.ticks += 1;
+        if self.decay_interval > 0 && self.ticks >= self.decay_interval {
+            self.ticks = 0;
+            self.decay();
+        }
+
+        // avoid allocating the key on every tick
+        if !self.counters.contains_key(func) {
+            self.counters.insert(func.into(), BTreeMap::new());
+        }
+
+        let counter = self.counters.get_mut(func).unwrap().entry(pc).or_insert(0);
+        *counter += 1;
+        *counter >= self.threshold
+    }
+
+    pub fn reset(&mut self, func: &str, pc: usize) {
+        if let Some(loops) = self.counters.get_mut(func) {
+            loops.remove(&pc);
+        }
+    }
+
+    /// Counts an aborted recording of the loop, returns `true` if the loop
+    /// got blacklisted.
+    pub fn abort(&mut self, func: &str, pc: usize) -> bool {
+        let aborts = self.aborts
+            .entry(func.into())
+            .or_default()
+            .entry(pc)
+            .or_insert(0);
+        *aborts += 1;
+
+        if *aborts == self.abort_threshold {
+            self.reset(func, pc);
+            return true;
+        }
+        false
+    }
+
+    /// Blacklists the loop, even if blacklisting after aborts is disabled.
+    pub fn blacklist(&mut self, func: &str, pc: usize) {
+        self.reset(func, pc);
+        self.blacklist.entry(func.into()).or_default().insert(pc);
+    }
+
+    pub fn is_blacklisted(&self, func: &str, pc: usize) -> bool {
+        if self.blacklist.get(func).is_some_and(|loops| loops.contains(&pc)) {
+            return true;
+        }
+
+        self.abort_threshold > 0 && self.aborts
+            .get(func)
+            .and_then(|loops| loops.get(&pc))
+            .is_some_and(|aborts| *aborts >= self.abort_threshold)
+    }
+
+    /// All loop headers with aborted recordings, as `(function, pc, aborts)`.
+    pub fn aborts(&self) -> impl Iterator<Item = (&str, usize, u32)> {
+        self.aborts.iter().flat_map(|(func, loops)| {
+            loops.iter().map(move |(pc, aborts)| (func.as_str(), *pc, *aborts))
+        })
+    }
+
+    /// All loop headers with a counter, as `(function, pc, count)`.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize, u32)> {
+        self.counters.iter().flat_map(|(func, loops)| {
+            loops.iter().map(move |(pc, count)| (func.as_str(), *pc, *count))
+        })
+    }
+
+    fn decay(&mut self) {
+        for loops in self.counters.values_mut() {
+            for counter in loops.values_mut() {
+                *counter /= 2;
+            }
+            // forget about loops which have cooled down completely
+            loops.retain(|_, counter| *counter > 0);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::HotCounters;
+
+    fn counts(counters: &HotCounters) -> Vec<(&str, usize, u32)> {
+        counters.iter().collect()
+    }
+
+    #[test]
+    fn loops_get_hot_at_the_threshold() {
+        let mut counters = HotCounters::new(3, 0, 0);
+        assert!(!counters.tick("f", 1));
+        assert!(!counters.tick("f", 1));
+        assert!(!counters.tick("g", 1));
+        assert!(counters.tick("f", 1));
+    }
+
+    #[test]
+    fn counters_decay() {
+        let mut counters = HotCounters::new(4, 4, 0);
+        for _ in 0..3 {
+            counters.tick("f", 1);
+        }
+
+        // the fourth tick halves the counter before counting
+        assert!(!counters.tick("f", 1));
+        assert_eq!(counts(&counters), vec![("f", 1, 2)]);
+    }
+
+    #[test]
+    fn cold_loops_are_forgotten() {
+        let mut counters = HotCounters::new(10, 2, 0);
+        counters.tick("f", 1);
+        counters.tick("f", 2);
+        assert_eq!(counts(&counters), vec![("f", 2, 1)]);
+    }
+
+    #[test]
+    fn blacklisted_loops_are_never_hot() {
+        let mut counters = HotCounters::new(1, 0, 2);
+        assert!(!counters.abort("f", 1));
+        assert!(counters.abort("f", 1));
+        assert!(counters.is_blacklisted("f", 1));
+        assert!(!counters.tick("f", 1));
+        assert_eq!(counters.aborts().collect::<Vec<_>>(), vec![("f", 1, 2)]);
+    }
+
+    #[test]
+    fn blacklisting_does_not_need_aborts() {
+        let mut counters = HotCounters::new(1, 0, 0);
+        counters.blacklist("f", 1);
+        assert!(!counters.tick("f", 1));
+        assert!(counters.tick("f", 2));
+    }
+}
@@ -0,0 +1,421 @@
+//! Trace intermediate representation
+//!
+//! Recorded traces (`TraceInstruction`s, a stack machine) are translated into
+//! SSA form: every instruction defines at most one value and operands refer
+//! to the instructions defining them (`Ref`, the index of the instruction in
+//! the trace). The operand stack disappears during translation.
+//!
+//! Locals stay in memory: `Load` and `Store` access the slots of the trace
+//! (see `TraceDataAllocator`). Values only live for one iteration of the
+//! trace, everything carried over to the next iteration goes through slots.
+//! The exception are peeled traces (see `opt/peel.rs`): the loop starts
+//! after a preamble, and values defined by the preamble are available in
+//! every iteration.
+//!
+//! Instructions which can leave the trace carry a `Snapshot` of the operand
+//! stack, which has to be reconstructed for the interpreter. The interpreter
+//! has one stack for all frames, a snapshot lists the values of the loop
+//! frame and of all inlined frames, exactly as they are expected by the
+//! instruction the interpreter resumes with. Snapshots also
+//! hold stores which were removed from the trace (see `opt/dse.rs`), but
+//! have to be observable when the trace is left.
+
+use std::fmt::{self, Write};
+
+use bytecode::Comp;
+use recovery::Guard;
+
+use super::{Check, LoopCall, TraceInstruction};
+
+
+/// Index of the instruction defining a value.
+pub type Ref = usize;
+
+/// State of the trace, which is not kept in slots.
+#[derive(Clone, Default)]
+pub struct Snapshot {
+    // values on the operand stack of the trace, bottom first
+    pub stack: Vec<Ref>,
+    // slot -> value, stores to perform before leaving the trace
+    pub slots: Vec<(usize, Ref)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Ir {
+    Const(usize),
+    ConstBool(bool),
+    Load(usize),
+    Store(usize, Ref),
+
+    // guarded by `Overflows`, unless proven not to overflow (see
+    // `opt/bce.rs`), backends don't check again
+    Add(Ref, Ref),
+    // whether adding the values overflows
+    Overflows(Ref, Ref),
+    // left, right
+    Cmp(Comp, Ref, Ref),
+    // arrays are values, `Push` defines a new array
+    NewArray(usize),
+    // array, value
+    Push(Ref, Ref),
+    // array, index. The index is checked by a guard, backends don't check
+    // again
+    ArrayGet(Ref, Ref),
+    Len(Ref),
+    // array which is not allocated (see `opt/sink.rs`), only materialized
+    // from its elements when the trace is left
+    Sunk(Vec<Ref>),
+
+    // value on the operand stack when entering a bridge, bottom first
+    Entry(usize),
+
+    Guard(Ref, Guard, Snapshot),
+    // guard which always fails
+    Exit(Guard, Snapshot),
+    CallLoop(LoopCall, Snapshot),
+
+    // removed by an optimization, see `compact`
+    Nop,
+}
+
+impl Ir {
+    /// Values used by the instruction.
+    pub fn operands(&self) -> Vec<Ref> {
+        match *self {
+            Ir::Const(_) | Ir::ConstBool(_) | Ir::NewArray(_) | Ir::Load(_) | Ir::Entry(_) | Ir::Nop => vec![],
+            Ir::Store(_, val) => vec![val],
+            Ir::Add(a, b) | Ir::Overflows(a, b) | Ir::Cmp(_, a, b) | Ir::Push(a, b) | Ir::ArrayGet(a, b) => vec![a, b],
+            Ir::Len(xs) => vec![xs],
+            Ir::Sunk(ref elements) => elements.clone(),
+            Ir::Guard(cond, _, ref snapshot) => {
+                let mut refs = vec![cond];
+                refs.extend(snapshot.operands());
+                refs
+            }
+            Ir::Exit(_, ref snapshot) | Ir::CallLoop(_, ref snapshot) => snapshot.operands(),
+        }
+    }
+
+    /// Replaces every operand `val` with `f(val)`.
+    pub fn map_operands<F: FnMut(Ref) -> Ref>(&mut self, mut f: F) {
+        match *self {
+            Ir::Const(_) | Ir::ConstBool(_) | Ir::NewArray(_) | Ir::Load(_) | Ir::Entry(_) | Ir::Nop => (),
+            Ir::Store(_, ref mut val) => *val = f(*val),
+            Ir::Len(ref mut xs) => *xs = f(*xs),
+            Ir::Sunk(ref mut elements) => {
+                for val in elements {
+                    *val = f(*val);
+                }
+            }
+            Ir::Add(ref mut a, ref mut b) |
+            Ir::Overflows(ref mut a, ref mut b) |
+            Ir::Cmp(_, ref mut a, ref mut b) |
+            Ir::Push(ref mut a, ref mut b) |
+            Ir::ArrayGet(ref mut a, ref mut b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Ir::Guard(ref mut cond, _, ref mut snapshot) => {
+                *cond = f(*cond);
+                snapshot.map(f);
+            }
+            Ir::Exit(_, ref mut snapshot) | Ir::CallLoop(_, ref mut snapshot) => snapshot.map(f),
+        }
+    }
+}
+
+impl Snapshot {
+    fn of(stack: &[Ref]) -> Self {
+        Snapshot {
+            stack: stack.to_vec(),
+            slots: Vec::new(),
+        }
+    }
+
+    fn operands(&self) -> Vec<Ref> {
+        self.stack.iter().cloned().chain(self.slots.iter().map(|&(_, val)| val)).collect()
+    }
+
+    fn map<F: FnMut(Ref) -> Ref>(&mut self, mut f: F) {
+        for val in &mut self.stack {
+            *val = f(*val);
+        }
+        for &mut (_, ref mut val) in &mut self.slots {
+            *val = f(*val);
+        }
+    }
+}
+
+// the stack, followed by pending stores (if any)
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.stack)?;
+        for &(slot, val) in &self.slots {
+            write!(f, " [{}]={:04}", slot, val)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Translates a recorded trace, `entry_stack` values are on the operand
+/// stack when the trace is entered (only bridges have those).
+pub fn build(instrs: &[TraceInstruction], entry_stack: usize) -> Vec<Ir> {
+    use TraceInstruction as TI;
+
+    let mut ir = Vec::with_capacity(instrs.len());
+    let mut stack = Vec::new();
+
+    for idx in 0..entry_stack {
+        stack.push(ir.len());
+        ir.push(Ir::Entry(idx));
+    }
+
+    for instr in instrs {
+        let op = match *instr {
+            TI::Const(n) => Ir::Const(n),
+            TI::Load(slot) => Ir::Load(slot),
+            TI::Store(slot) => Ir::Store(slot, stack.pop().unwrap()),
+
+            TI::Add => {
+                let (a, b) = pop_2(&mut stack);
+                Ir::Add(a, b)
+            }
+            // like the interpreter, the top of the stack is the left operand
+            TI::Cmp(how) => {
+                let (left, right) = pop_2(&mut stack);
+                Ir::Cmp(how, left, right)
+            }
+            TI::ArrayGet => {
+                let (index, xs) = pop_2(&mut stack);
+                Ir::ArrayGet(xs, index)
+            }
+            TI::Array(capacity) => Ir::NewArray(capacity),
+            TI::Push => {
+                let (val, xs) = pop_2(&mut stack);
+                Ir::Push(xs, val)
+            }
+            TI::Len => Ir::Len(stack.pop().unwrap()),
+
+            TI::Guard(ref guard) => {
+                let cond = stack.pop().unwrap();
+                let mut snapshot = Snapshot::of(&stack);
+                if guard.condition_on_stack {
+                    snapshot.stack.push(cond);
+                }
+                Ir::Guard(cond, guard.clone(), snapshot)
+            }
+            // the guard resumes at the checked instruction, with its operands
+            // on the stack
+            TI::Check(check, ref guard) => {
+                let (top, below) = (stack[stack.len() - 1], stack[stack.len() - 2]);
+                let cond = match check {
+                    Check::Add | Check::IncLocal => push(&mut ir, Ir::Overflows(below, top)),
+                    Check::Index => {
+                        let len = push(&mut ir, Ir::Len(below));
+                        push(&mut ir, Ir::Cmp(Comp::Lt, top, len))
+                    }
+                };
+                let operands = if let Check::IncLocal = check { 2 } else { 0 };
+                Ir::Guard(cond, guard.clone(), Snapshot::of(&stack[..stack.len() - operands]))
+            }
+            TI::CallLoop(ref call) => Ir::CallLoop(call.clone(), Snapshot::of(&stack)),
+
+            TI::Clone => continue,
+
+            // recording stops before these
+            TI::Print => unreachable!(),
+        };
+
+        if defines_value(&op) {
+            stack.push(ir.len());
+        }
+        ir.push(op);
+    }
+
+    debug_assert!(stack.is_empty(), "values left on the stack: {:?}", stack);
+    ir
+}
+
+/// Values of `snapshot` on the stack after the guarded instruction has been
+/// evaluated, i.e. where the interpreter (or a bridge) continues with
+/// `guard.exit_pc`.
+pub fn exit_stack<'a>(guard: &Guard, snapshot: &'a Snapshot) -> &'a [Ref] {
+    let stack = &snapshot.stack;
+    if guard.condition_on_stack {
+        &stack[..stack.len() - 1]
+    } else {
+        stack
+    }
+}
+
+/// How the value of an instruction is kept by the runner.
+pub enum Repr<'a> {
+    Usize,
+    Bool,
+    // `Value`, type only known at runtime
+    Boxed,
+    // not computed, an array of these (unboxed) elements
+    Sunk(&'a [Ref]),
+}
+
+pub fn repr<'a>(op: &'a Ir) -> Repr<'a> {
+    match *op {
+        Ir::Const(_) | Ir::Add(..) | Ir::ArrayGet(..) | Ir::Len(_) => Repr::Usize,
+        Ir::ConstBool(_) | Ir::Overflows(..) | Ir::Cmp(..) => Repr::Bool,
+        Ir::Sunk(ref elements) => Repr::Sunk(elements),
+        _ => Repr::Boxed,
+    }
+}
+
+/// Whether the instruction defines a value.
+pub fn defines_value(op: &Ir) -> bool {
+    !matches!(*op, Ir::Store(..) | Ir::Guard(..) | Ir::Exit(..) | Ir::CallLoop(..) | Ir::Nop)
+}
+
+/// Removes `Nop`s and renumbers the operands of the remaining instructions,
+/// `loop_start` is moved accordingly.
+pub fn compact(ir: &mut Vec<Ir>, loop_start: &mut usize) {
+    let mut new_refs = Vec::with_capacity(ir.len());
+    let mut count = 0;
+    for (idx, op) in ir.iter().enumerate() {
+        if idx == *loop_start {
+            *loop_start = count;
+        }
+        new_refs.push(count);
+        if !matches!(*op, Ir::Nop) {
+            count += 1;
+        }
+    }
+    if *loop_start >= ir.len() {
+        *loop_start = count;
+    }
+
+    ir.retain(|op| !matches!(*op, Ir::Nop));
+    for op in ir.iter_mut() {
+        op.map_operands(|val| new_refs[val]);
+    }
+}
+
+/// Checks that the trace is well formed: operands are values defined by
+/// earlier instructions, `Entry` values come first and slots are within the
+/// `locals_count` slots of the trace.
+pub fn verify(ir: &[Ir], locals_count: usize) -> Result<(), String> {
+    let mut entries = true;
+
+    for (idx, op) in ir.iter().enumerate() {
+        for val in op.operands() {
+            if val >= idx || !defines_value(&ir[val]) {
+                return Err(format!("{:04}: invalid operand {:04}", idx, val));
+            }
+        }
+
+        if let Some(snapshot) = snapshot(op) {
+            if let Some(&(slot, _)) = snapshot.slots.iter().find(|&&(slot, _)| slot >= locals_count) {
+                return Err(format!("{:04}: snapshot slot {:} out of range", idx, slot));
+            }
+        }
+
+        match *op {
+            Ir::Entry(_) if !entries => return Err(format!("{:04}: entry value after the start", idx)),
+            Ir::Load(slot) | Ir::Store(slot, _) if slot >= locals_count => {
+                return Err(format!("{:04}: slot {:} out of range", idx, slot));
+            }
+            Ir::Nop => return Err(format!("{:04}: nop", idx)),
+            _ => (),
+        }
+        entries &= matches!(*op, Ir::Entry(_));
+    }
+
+    Ok(())
+}
+
+/// One instruction per line, for logging.
+pub fn dump(ir: &[Ir]) -> String {
+    let mut out = String::new();
+    for (idx, op) in ir.iter().enumerate() {
+        let _ = match *op {
+            Ir::Guard(cond, ref guard, ref snapshot) => {
+                writeln!(out, "{:04}       guard {:04} {:?} {:?}", idx, cond, guard, snapshot)
+            }
+            Ir::Exit(ref guard, ref snapshot) => {
+                writeln!(out, "{:04}       exit {:?} {:?}", idx, guard, snapshot)
+            }
+            Ir::CallLoop(ref call, ref snapshot) => {
+                writeln!(out, "{:04}       call_loop {:?} {:?}", idx, call, snapshot)
+            }
+            ref op if defines_value(op) => writeln!(out, "{:04} = {:?}", idx, op),
+            ref op => writeln!(out, "{:04}       {:?}", idx, op),
+        };
+    }
+    out
+}
+
+/// Snapshot of instructions which can leave the trace.
+pub fn snapshot(op: &Ir) -> Option<&Snapshot> {
+    match *op {
+        Ir::Guard(_, _, ref snapshot) | Ir::Exit(_, ref snapshot) | Ir::CallLoop(_, ref snapshot) => Some(snapshot),
+        _ => None,
+    }
+}
+
+fn push(ir: &mut Vec<Ir>, op: Ir) -> Ref {
+    ir.push(op);
+    ir.len() - 1
+}
+
+fn pop_2(stack: &mut Vec<Ref>) -> (Ref, Ref) {
+    (stack.pop().unwrap(), stack.pop().unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use recovery::Guard;
+    use testing;
+
+    use super::{exit_stack, Ir, Snapshot};
+
+    /// Guard of a conditional jump at 3, continuing at 6 if it fails.
+    fn guard(condition_on_stack: bool) -> Guard {
+        Guard {
+            condition: true,
+            frames: 0,
+            pc: 3,
+            condition_on_stack,
+            loop_exit: false,
+            exit_pc: 6,
+        }
+    }
+
+    #[test]
+    fn exit_stacks_leave_out_the_condition() {
+        let snapshot = Snapshot { stack: vec![4, 7], slots: vec![] };
+
+        assert_eq!(exit_stack(&guard(true), &snapshot), [4]);
+        assert_eq!(exit_stack(&guard(false), &snapshot), [4, 7]);
+    }
+
+    #[test]
+    fn bridges_start_with_the_exit_stack() {
+        // `total` is on the stack when the branch in `pick` changes
+        let (_, _, trace) = testing::trace_of("pending_call", &["--bridge-threshold=1"]);
+        let exits = trace.exits.borrow();
+        let bridges = exits.iter()
+            .filter_map(|(&idx, exit)| exit.bridge.as_ref().map(|bridge| (idx, bridge)))
+            .collect::<Vec<_>>();
+        assert!(!bridges.is_empty());
+
+        for (idx, bridge) in bridges {
+            let entries = bridge.trace.iter().filter(|op| matches!(**op, Ir::Entry(_))).count();
+
+            let (guard, snapshot) = match trace.trace[idx] {
+                Ir::Guard(_, ref guard, ref snapshot) => (guard, snapshot),
+                ref op => panic!("exit at {:?}", op),
+            };
+            assert!(guard.condition_on_stack);
+            assert_eq!(snapshot.stack.len(), 2);
+            assert_eq!(entries, 1);
+        }
+    }
+}
@@ -10,29 +10,65 @@ extern crate env_logger;
 extern crate boolinator;
 extern crate kaktus;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
 use std::rc::Rc;
 
 use kaktus::{PushPop, Stack};
 
 use bytecode::{Instruction, Comp};
-use recovery::{Guard, FrameInfo};
+use config::Config;
+use encoding::CompactModule;
+use abort::Abort;
+use error::VmError;
+use hotness::HotCounters;
+use opt::Optimizer;
+use ir::{Ir, Ref};
+use recorder::{Recorder, TraceDataAllocator};
+use recovery::{DeoptTable, Guard, FrameInfo};
+use tracecache::TraceCache;
 use tracerunner::Runner;
-use repr::{CallFrame, Func, InstrPtr, Value};
+use validate::Shadow;
+use repr::{CallFrame, Func, InstrPtr, Type, Value};
 
 use traits::vec::ConvertingStack;
 
+mod abort;
+mod bench;
 mod bytecode;
+mod check;
+mod closures;
+mod config;
 mod conversions;
+mod encoding;
+mod error;
+mod hotness;
+mod native;
+mod opt;
+mod ir;
+mod peephole;
+mod programs;
+mod recorder;
 mod recovery;
+mod regalloc;
+mod tracecache;
 mod tracerunner;
+mod validate;
 mod traits;
 mod repr;
+mod superinstructions;
+#[cfg(test)]
+mod testing;
 
 
-pub type TraceMap = BTreeMap<usize, Trace>;
 pub type ModuleMap = BTreeMap<String, Rc<Func>>;
 
+/// Instruction the interpreter continues with, `None` if the program has
+/// ended, or the error the executed instruction raised.
+type Next = Result<Option<InstrPtr>, VmError>;
+
 
 pub struct Module {
     funcs: ModuleMap,
@@ -41,17 +77,130 @@ pub struct Module {
 
 #[derive(Debug)]
 pub struct Trace {
-    pub trace: Vec<TraceInstruction>,
+    // see `ir.rs`
+    pub trace: Vec<Ir>,
     pub locals_count: usize,
+    // the end of the trace continues at its start, false for bridges
+    pub looping: bool,
+    // where the end of the trace continues, after a preamble
+    pub loop_start: usize,
+    // register of the value of every instruction (see `regalloc.rs`)
+    pub registers: Vec<usize>,
+    pub register_count: usize,
+    // slots the trace never writes, none if it calls inner traces. The
+    // runner reads arrays in them in place (see `tracerunner::Slots`).
+    pub read_only: Vec<bool>,
+    // entry guards: slot and type of every local of the loop frame, which
+    // is read before it is written. The trace is specialized for these types
+    // and only entered, if the locals have them.
+    pub entry_types: Vec<(usize, Type)>,
+    // index of guard in `trace` -> what happened when it failed
+    pub exits: RefCell<BTreeMap<usize, SideExit>>,
+    // frames to recover, guards refer to them by index (see
+    // `recovery::DeoptTable`)
+    pub deopt: DeoptTable,
+    // machine code of the trace, with `--native` (see `native`)
+    pub native: Option<native::Code>,
+    // with `--closures` (see `closures`)
+    pub closures: Option<closures::Closures>,
+}
+
+/// Statistics and side trace of a guard.
+#[derive(Debug, Default)]
+pub struct SideExit {
+    pub failures: u32,
+    // side trace, starting where the interpreter would continue after the
+    // guard failed and ending at the loop header of the trace
+    pub bridge: Option<Rc<Trace>>,
+    // recording a bridge failed, don't try again
+    pub blacklisted: bool,
 }
 
 impl Trace {
-    fn new(trace: Vec<TraceInstruction>, locals_count: usize) -> Self {
+    fn new(trace: Vec<Ir>, locals_count: usize, deopt: DeoptTable) -> Self {
         Trace {
-            trace: trace,
-            locals_count: locals_count,
+            trace,
+            locals_count,
+            looping: true,
+            loop_start: 0,
+            registers: Vec::new(),
+            register_count: 0,
+            read_only: Vec::new(),
+            entry_types: Vec::new(),
+            exits: RefCell::new(BTreeMap::new()),
+            deopt,
+            native: None,
+            closures: None,
         }
     }
+
+    /// Number of guards and loop calls, which refer to the deopt table.
+    pub fn guard_count(&self) -> usize {
+        self.trace
+            .iter()
+            .filter(|instr| matches!(**instr, Ir::Guard(..) | Ir::Exit(..) | Ir::CallLoop(..)))
+            .count()
+    }
+
+    /// Whether the entry guards hold for the `locals` of the loop frame.
+    pub fn accepts(&self, locals: &[Value]) -> bool {
+        self.entry_types.iter().all(|&(slot, ty)| locals[slot].ty() == ty)
+    }
+
+    /// Slots below `size`, which are read before they are written.
+    fn live_in(&self, size: usize) -> BTreeSet<usize> {
+        let mut written = vec![false; size];
+        let mut live = BTreeSet::new();
+
+        for instr in &self.trace {
+            match *instr {
+                Ir::Load(slot) if slot < size && !written[slot] => {
+                    live.insert(slot);
+                }
+                Ir::Store(slot, _) if slot < size => written[slot] = true,
+                // the inner trace reads its live locals
+                Ir::CallLoop(ref call, _) => {
+                    for &(slot, _) in &call.trace.entry_types {
+                        let slot = call.offset + slot;
+                        if slot < size && !written[slot] {
+                            live.insert(slot);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        live
+    }
+
+    /// Approximate number of bytes used by the trace.
+    pub fn memory_size(&self) -> usize {
+        let snapshots = self.trace
+            .iter()
+            .map(|instr| match *instr {
+                Ir::Guard(_, _, ref snapshot) |
+                Ir::Exit(_, ref snapshot) |
+                Ir::CallLoop(_, ref snapshot) => {
+                    snapshot.stack.len() * mem::size_of::<Ref>() + snapshot.slots.len() * mem::size_of::<(usize, Ref)>()
+                }
+                _ => 0,
+            })
+            .sum::<usize>();
+
+        let bridges = self.exits
+            .borrow()
+            .values()
+            .map(|exit| mem::size_of::<SideExit>() + exit.bridge.as_ref().map_or(0, |b| b.memory_size()))
+            .sum::<usize>();
+
+        mem::size_of::<Trace>() + self.trace.len() * mem::size_of::<Ir>() +
+        self.entry_types.len() * mem::size_of::<(usize, Type)>() + snapshots +
+        self.registers.len() * mem::size_of::<usize>() +
+        bridges + self.deopt.size() +
+        self.native.as_ref().map_or(0, |code| code.size()) +
+        self.closures.as_ref().map_or(0, |closures| closures.size())
+    }
 }
 
 
@@ -74,297 +223,614 @@ pub enum TraceInstruction {
     Clone,
 
     Guard(Guard),
-}
-
-
+    // guards against the error the following instruction could raise
+    Check(Check, Guard),
 
-struct TraceDataAllocator {
-    total_size: usize,
-    offsets: Vec<usize>,
+    // executes the trace of an inner loop until it leaves the loop
+    CallLoop(LoopCall),
 }
 
-impl TraceDataAllocator {
-    fn new() -> Self {
-        TraceDataAllocator {
-            total_size: 0,
-            offsets: Vec::new(),
-        }
-    }
-
-    fn alloc(&mut self, to_allocate: usize) {
-        self.offsets.push(self.total_size);
-        // reserve space at the end
-        self.total_size += to_allocate;
-    }
-
-    fn pop(&mut self) {
-        self.offsets.pop().unwrap();
-    }
+/// Operations checked by the trace (see `TraceInstruction::Check`), their
+/// operands are the top two values of the stack.
+#[derive(Debug, Clone, Copy)]
+pub enum Check {
+    // `Add` does not overflow
+    Add,
+    // same as `Add`, but the operands are not on the stack of the interpreter
+    IncLocal,
+    // the index of `ArrayGet` is in bounds
+    Index,
+}
 
-    fn current(&self) -> usize {
-        *self.offsets.last().unwrap()
-    }
+/// Call of an inner loop trace from an outer trace.
+///
+/// The inner trace addresses its locals relative to `offset`, which is the
+/// offset of the frame containing the inner loop in the outer trace.
+#[derive(Clone)]
+pub struct LoopCall {
+    pub trace: Rc<Trace>,
+    pub offset: usize,
+    // (inlined) frames of the outer trace, index into its `DeoptTable`. The
+    // innermost one contains the inner loop. Used to recover guards failing
+    // in the inner trace.
+    pub frames: usize,
+    // `Loop` instruction of the inner loop
+    pub header: InstrPtr,
+}
 
-    fn at(&self, idx: usize) -> usize {
-        self.current() + idx
+impl fmt::Debug for LoopCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "+{:} ({:} instructions)", self.offset, self.trace.trace.len())
     }
 }
 
 
+
 pub struct Interpreter<'a> {
     module: &'a Module,
+    config: Config,
     stack: Vec<Value>,
     frames: Vec<CallFrame>,
+    // values written by `Print`
+    output: Vec<usize>,
+    traces: TraceCache,
+    hot_counters: HotCounters,
+    // set while recording a trace
+    recorder: Option<Recorder>,
+    optimizer: Optimizer,
 }
 
 impl<'a> Interpreter<'a> {
-    fn new(module: &'a Module) -> Self {
+    fn with_config(module: &'a Module, config: Config) -> Self {
         Interpreter {
-            module: module,
+            module,
             stack: Vec::new(),
             frames: Vec::new(),
+            output: Vec::new(),
+            traces: TraceCache::new(config.trace_cache_size),
+            hot_counters: HotCounters::new(config.hot_threshold, config.hot_decay, config.abort_threshold),
+            recorder: None,
+            optimizer: Optimizer::new(&config),
+            config,
         }
     }
 
-    fn get_fn(&self, name: &str) -> Rc<Func> {
-        self.module.funcs[name].clone()
+    pub fn output(&self) -> &[usize] {
+        &self.output
     }
 
-    // XXX: why do I return func, pc? shouldn't that be the same as the input?
-    fn trace(&mut self, instr: &InstrPtr) -> (InstrPtr, Trace) {
-        use Instruction::*;
+    pub fn traces(&self) -> &TraceCache {
+        &self.traces
+    }
 
-        let mut trace = Vec::new();
+    pub fn traces_mut(&mut self) -> &mut TraceCache {
+        &mut self.traces
+    }
 
-        let mut call_tree = Stack::root(FrameInfo {
-            func: instr.func.clone(),
+    fn get_fn(&self, name: &str) -> Rc<Func> {
+        self.module.funcs[name].clone()
+    }
+
+    /// Records a trace for the loop starting at `header`.
+    fn trace(&mut self, header: &InstrPtr) -> (Next, Result<Trace, Abort>) {
+        let call_tree = Stack::root(FrameInfo {
+            func: header.func.clone(),
             back_ref: self.frames.last().unwrap().back_ref.clone(),
             offset: 0,
         });
 
         let mut locals = TraceDataAllocator::new();
-        locals.alloc(instr.func.args_count + instr.func.locals_count);
+        locals.alloc(header.func.args_count + header.func.locals_count);
+
+        let types = self.frames.last().unwrap().locals.iter().map(Value::ty).collect::<Vec<_>>();
+
+        let (next, trace) = self.record(header.next(), Recorder::new(header, call_tree, locals, 0));
+        let trace = trace.map(|mut trace| {
+            trace.entry_types = trace.live_in(types.len())
+                .into_iter()
+                .map(|slot| (slot, types[slot]))
+                .collect();
+            self.optimizer.run(&mut trace);
+            self.compile(&mut trace);
+            trace
+        });
+        (next, trace)
+    }
 
-        let mut next = instr.clone();
+    /// Records a bridge for the guard at `trace.trace[idx]`, the interpreter
+    /// state has been recovered to continue with `start`.
+    fn trace_bridge(&mut self, header: &InstrPtr, trace: &Trace, idx: usize, start: InstrPtr) -> Next {
+        let (call_tree, entry_stack) = match trace.trace[idx] {
+            Ir::Guard(_, ref guard, ref snapshot) |
+            Ir::Exit(ref guard, ref snapshot) => {
+                // the loop frame is the least recent of the recovered frames
+                let depth = trace.deopt.frames(guard.frames).len();
+                let back_ref = self.frames[self.frames.len() - depth].back_ref.clone();
+                (trace.deopt.call_tree(guard.frames, back_ref), ir::exit_stack(guard, snapshot).len())
+            }
+            _ => panic!("no guard at {:?}", idx),
+        };
+
+        // use new slots for frames inlined by the bridge
+        let locals = TraceDataAllocator::for_frames(&call_tree, trace.locals_count);
+
+        info!("T: recording bridge for guard {:} @{:}[{:}]", idx, header.func.name, header.pc);
+        // the bridge starts with the operand stack of the guard
+        let recorder = Recorder::new(header, call_tree, locals, entry_stack);
+        let (next, bridge) = self.record(start, recorder);
+
+        let mut exits = trace.exits.borrow_mut();
+        let exit = exits.get_mut(&idx).unwrap();
+        match bridge {
+            Ok(mut bridge) => {
+                bridge.looping = false;
+                self.optimizer.run(&mut bridge);
+                self.compile(&mut bridge);
+                exit.bridge = Some(Rc::new(bridge));
+            }
+            Err(abort) => {
+                info!("T: recording bridge aborted: {}", abort);
+                exit.blacklisted = true;
+            }
+        }
+        next
+    }
 
+    /// Compiles the optimized `trace` for the configured backends, the
+    /// runner picks native code over closures over interpreting the IR.
+    fn compile(&self, trace: &mut Trace) {
+        if self.config.native {
+            trace.native = native::compile(trace);
+        }
+        if self.config.closures {
+            trace.closures = Some(closures::compile(trace));
+        }
+    }
+
+    /// Executes instructions in recording mode, starting with `instr`, until
+    /// the loop header of `recorder` is reached again.
+    ///
+    /// Inner loops are executed by their trace and called from the recorded
+    /// trace (`TraceInstruction::CallLoop`).
+    ///
+    /// Recording is aborted (see `Abort`), e.g. if the loop is left or an
+    /// inner loop has no trace (yet). Either way, the interpreter continues
+    /// with the returned instruction, which has not been executed yet.
+    ///
+    /// Recording usually stops before the program ends or an instruction
+    /// fails (see `recording_stops`). Should it happen anyway, the recorded
+    /// trace is discarded and the interpreter ends as well.
+    fn record(&mut self, instr: InstrPtr, recorder: Recorder) -> (Next, Result<Trace, Abort>) {
+        self.recorder = Some(recorder);
+
+        let mut next = instr;
         loop {
-            let instr = next;
-            next = instr.next();
+            next = match self.step(&next) {
+                Ok(Some(next)) => next,
+                ended => {
+                    self.recorder = None;
+                    let abort = match ended {
+                        Err(ref error) => Abort::Exception(error.clone()),
+                        _ => Abort::ProgramEnded,
+                    };
+                    return (ended, Err(abort));
+                }
+            };
 
-            info!(target: "exec","TRACE: {:?}", instr);
+            if self.recorder.as_ref().unwrap().stopped() {
+                let recorder = self.recorder.take().unwrap();
+                return (Ok(Some(next)), recorder.into_result());
+            }
+        }
+    }
 
-            match *instr {
-                Loop => break,
+    /// Checks whether recording has to stop before executing `instr`, either
+    /// because the trace is complete (`Ok`) or because the trace can't
+    /// continue with `instr`.
+    fn recording_stops(&self, instr: &InstrPtr) -> Option<Result<(), Abort>> {
+        use Instruction::*;
 
-                Break => unimplemented!(),
+        let recorder = self.recorder.as_ref().unwrap();
 
-                Clone => (),
+        if recorder.at_header(instr) {
+            return Some(Ok(()));
+        }
 
-                Const(n) => self.do_const(n),
-                Add => self.do_add(),
+        if recorder.trace.len() > self.config.max_trace_length {
+            return Some(Err(Abort::TooLong));
+        }
 
-                Load(idx) => {
-                    self.do_load(idx);
-                    trace.push(TraceInstruction::Load(locals.at(idx)));
-                    continue;
-                }
+        if let Some(error) = self.fails(instr) {
+            return Some(Err(Abort::Exception(error)));
+        }
 
-                Store(idx) => {
-                    self.do_store(idx);
-                    trace.push(TraceInstruction::Store(locals.at(idx)));
-                    continue;
-                }
+        let abort = match **instr {
+            // the loop was left before reaching the loop header again, the
+            // recorded path does not loop
+            Break => Abort::LoopLeft,
+            // returning from the function containing the loop
+            Return if recorder.locals.depth() == 1 => Abort::LoopLeft,
 
-                Print => self.do_print(),
+            Call(_) if recorder.locals.depth() > self.config.max_inline_depth => Abort::TooDeep,
+            Call(ref target) if recorder.call_tree.walk().any(|frame| frame.func.name == *target) => {
+                Abort::Recursion(target.clone())
+            }
 
-                Array(size) => self.do_array(size),
+            // the trace runner does not implement these
+            Print | JumpIfTrue(_) => Abort::Unsupported((**instr).clone()),
 
-                Len => self.do_len(),
-                Push => self.do_push(),
+            _ => return None,
+        };
+        Some(Err(abort))
+    }
 
-                ArrayGet => self.do_array_get(),
+    /// Runs the program, with `--compact` on its compact encoding, unless
+    /// some operand does not fit into it (see `encoding.rs`).
+    fn run_program(&mut self) -> Result<(), VmError> {
+        if self.config.compact {
+            match CompactModule::encode(self.module) {
+                Ok(compact) => return self.run_compact(&compact),
+                Err(reason) => info!("E: running Instructions, module can't be encoded: {}", reason),
+            }
+        }
+        self.run()
+    }
 
-                Call(ref target) => {
-                    let new_func = &self.module.funcs[target];
-                    let mut frame = CallFrame::for_fn(new_func, next);
+    fn run(&mut self) -> Result<(), VmError> {
+        // `main` function has to exist
+        let main = self.get_fn("main");
 
-                    locals.alloc(frame.locals.len());
+        // a bit awkward, main would return to main
+        // maybe it would be better to have Option as back_ref
+        self.frames.push(CallFrame::for_fn(&main, InstrPtr::new(main.clone(), 0)));
 
-                    for idx in 0..frame.args_count {
-                        frame.locals[idx] = self.stack.pop().unwrap();
-                        trace.push(TraceInstruction::Store(locals.at(idx)));
-                    }
+        let mut next = InstrPtr::for_fn(main.clone());
+        let result = loop {
+            match self.step(&next) {
+                Ok(Some(instr)) => next = instr,
+                Ok(None) => break Ok(()),
+                Err(error) => break Err(error),
+            }
+        };
 
-                    call_tree = call_tree.push(FrameInfo {
-                        func: new_func.clone(),
-                        back_ref: frame.back_ref.clone(),
-                        offset: locals.current(),
-                    });
+        self.log_statistics();
+        result
+    }
 
-                    self.frames.push(frame);
-                    next = InstrPtr::for_fn(new_func.clone());
+    /// Executes `instr` and returns the instruction to continue with, `None`
+    /// if the program has ended, or the error executing `instr` raised.
+    ///
+    /// In recording mode, the executed instruction is also recorded. If
+    /// recording stops at `instr`, it is not executed but returned instead.
+    fn step(&mut self, instr: &InstrPtr) -> Next {
+        use Instruction::*;
 
-                    // don't add Call to trace
-                    continue;
-                }
+        let stops = if self.recorder.is_some() {
+            info!(target: "exec","TRACE: {:?}", instr);
+            self.recording_stops(instr)
+        } else {
+            info!("E: {:?}", **instr);
+            None
+        };
+
+        if let Some(result) = stops {
+            self.recorder.as_mut().unwrap().stop(result);
+            return Ok(Some(instr.clone()));
+        }
 
-                Return => {
-                    locals.pop();
+        // pre-set next instruction
+        let mut next = instr.next();
+
+        match **instr {
+            // XXX: do I care about break here?
+            Break => (),
+
+            // simple dispatch of opcodes to callbacks
+            Const(n)    => self.do_const(n),
+            Add         => self.do_add()?,
+            Load(idx)   => self.do_load(idx),
+            Store(idx)  => self.do_store(idx),
+            Print       => self.do_print(),
+            Array(size) => self.do_array(size),
+            Len         => self.do_len(),
+            Push        => self.do_push(),
+            ArrayGet    => self.do_array_get()?,
+            Cmp(how)    => self.do_cmp(how),
+            Clone       => (),
+
+            Loop => {
+                next = match self.enter_loop(instr)? {
+                    Some(next) => next,
+                    None => return Ok(None),
+                };
+            }
 
-                    let frame = self.frames.pop();
-                    if self.frames.is_empty() {
-                        break;
-                    }
+            Call(ref target) => {
+                let module = self.module;
+                let new_func = &module.funcs[target];
 
-                    call_tree = call_tree.pop().unwrap();
+                // inline the call
+                if let Some(ref mut recorder) = self.recorder {
+                    recorder.call(new_func, &next);
+                }
 
-                    next = frame.unwrap().back_ref;
+                self.do_call(new_func, next);
+                next = InstrPtr::for_fn(new_func.clone());
+            }
 
-                    // don't add Return to trace
-                    continue;
+            Return => {
+                if let Some(ref mut recorder) = self.recorder {
+                    recorder.ret();
                 }
 
-                Cmp(how) => self.do_cmp(how),
+                next = match self.do_return() {
+                    Some(back_ref) => back_ref,
+                    None => return Ok(None),
+                };
+            }
+
+            Jump(target) => {
+                next = instr.jump(target);
+            }
 
-                Jump(target) => {
-                    next = next.jump(target);
-                    // skip trace
-                    continue;
+            // recording stops before, see `recording_stops`
+            JumpIfTrue(target) => {
+                if self.stack.pop_into::<bool>() {
+                    next = instr.jump(target);
                 }
+            }
 
-                JumpIfFalse(target) => {
-                    let b: bool = self.stack.pop_into();
-                    if !bool::from(b) {
-                        next = next.jump(target);
-                    }
+            JumpIfFalse(target) => {
+                let b: bool = self.stack.pop_into();
+                if !b {
+                    next = instr.jump(target);
+                }
 
-                    let guard = Guard {
-                        condition: b,
-                        frame: call_tree.clone(),
-                        pc: instr.pc,
-                    };
-                    trace.push(TraceInstruction::Guard(guard));
-                    continue;
+                if let Some(ref mut recorder) = self.recorder {
+                    let exit_pc = if b { target } else { instr.pc + 1 };
+                    recorder.guard(b, instr, exit_pc, true);
+                }
+            }
+
+            CmpLocalsJumpIfFalse(a, b, how, target) => {
+                let cond = self.do_cmp_locals(a, b, how);
+                if !cond {
+                    next = instr.jump(target);
                 }
 
-                _ => panic!("TODO: {:?}", instr),
+                // superinstructions are recorded as the sequence they replace
+                if let Some(ref mut recorder) = self.recorder {
+                    recorder.emit_local(TraceInstruction::Load, a);
+                    recorder.emit_local(TraceInstruction::Load, b);
+                    recorder.emit(TraceInstruction::Cmp(how));
+
+                    // the superinstruction evaluates the condition itself
+                    let exit_pc = if cond { target } else { instr.pc + 1 };
+                    recorder.guard(cond, instr, exit_pc, false);
+                }
             }
 
-            trace.push(TraceInstruction::from(&*instr));
-        }
+            IncLocal(idx, n) => {
+                self.do_inc_local(idx, n)?;
 
-        info!(target: "trace", "{:?}", trace);
+                if let Some(ref mut recorder) = self.recorder {
+                    recorder.emit_local(TraceInstruction::Load, idx);
+                    recorder.emit(TraceInstruction::Const(n));
+                    recorder.check(Check::IncLocal, instr);
+                    recorder.emit(TraceInstruction::Add);
+                    recorder.emit_local(TraceInstruction::Store, idx);
+                }
+            }
+        }
 
-        (instr.clone(), Trace::new(trace, locals.total_size))
-    }
+        // no wildcard: new instructions have to be recorded or stop recording
+        if let Some(ref mut recorder) = self.recorder {
+            match **instr {
+                Load(idx) => recorder.emit_local(TraceInstruction::Load, idx),
+                Store(idx) => recorder.emit_local(TraceInstruction::Store, idx),
+                // the trace checks for the errors the interpreter would raise
+                Add => {
+                    recorder.check(Check::Add, instr);
+                    recorder.emit(TraceInstruction::Add);
+                }
+                ArrayGet => {
+                    recorder.check(Check::Index, instr);
+                    recorder.emit(TraceInstruction::ArrayGet);
+                }
+                Const(_) | Cmp(_) | Clone | Array(_) | Len => recorder.emit(TraceInstruction::from(&**instr)),
+                Push => recorder.emit(TraceInstruction::Push),
 
-    fn run(&mut self) {
-        use Instruction::*;
+                // recorded above, or control flow the trace follows implicitly
+                Call(_) | Return | Jump(_) | JumpIfFalse(_) | CmpLocalsJumpIfFalse(..) |
+                IncLocal(..) | Loop | Break => (),
 
-        // `main` function has to exist
-        let main = self.get_fn("main");
+                // recording stops before these, see `recording_stops`
+                Print | JumpIfTrue(_) => unreachable!(),
+            }
+        }
 
-        // a bit awkward, main would return to main
-        // maybe it would be better to have Option as back_ref
-        self.frames.push(CallFrame::for_fn(&main, InstrPtr::new(main.clone(), 0)));
+        Ok(Some(next))
+    }
 
-        let mut traces = TraceMap::new();
-        let mut next = InstrPtr::for_fn(main.clone());
+    /// Executes the loop starting at `instr` (a `Loop` instruction).
+    ///
+    /// Returns the instruction the interpreter has to continue with.
+    fn enter_loop(&mut self, instr: &InstrPtr) -> Next {
+        if !self.config.jit {
+            return Ok(Some(instr.next()));
+        }
 
-        loop {
-            // get next instruction
-            let instr = next;
-            // pre-set next instruction
-            next = instr.next();
+        if self.recorder.is_some() {
+            return Ok(Some(self.record_inner_loop(instr)));
+        }
 
-            info!("E: {:?}", *instr);
+        // do we already have a trace for this position?
+        let trace = self.traces.get(&instr.func.name, instr.pc, &self.frames.last().unwrap().locals);
+        if let Some(trace) = trace {
+            info!("T: running trace @{:}[{:}]", instr.func.name, instr.pc);
+            let (next, hot_exit, _) = self.run_trace(instr, &trace);
+            info!("T: return from trace to func {:?} pc {:?}", next.func.name, next.pc);
+            info!("T: STACK: {:?}", self.stack);
+            info!("T: FRAME: {:?}", self.frames.last().unwrap().locals);
+
+            if let Some(idx) = hot_exit {
+                let next = self.trace_bridge(instr, &trace, idx, next);
+                self.traces.refresh(&instr.func.name, instr.pc, &trace);
+                return next;
+            }
+            return Ok(Some(next));
+        }
 
-            match *instr {
-                // XXX: do I care about break here?
-                Break | Clone => (),
-
-                // simple dispatch of opcodes to callbacks
-                Const(n)    => self.do_const(n),
-                Add         => self.do_add(),
-                Load(idx)   => self.do_load(idx),
-                Store(idx)  => self.do_store(idx),
-                Print       => self.do_print(),
-                Array(size) => self.do_array(size),
-                Len         => self.do_len(),
-                Push        => self.do_push(),
-                ArrayGet    => self.do_array_get(),
-                Cmp(how)    => self.do_cmp(how),
-
-                // XXX: currently there is no threshhold value when to start tracing
-                // meaning that tracing starts immediately
-                Loop => {
-                    // do we already have a trace for this position?
-                    if let Some(trace) = traces.get(&instr.pc) {
-                        // we need this block, since Runner takes self as &mut
-                        {
-                            info!("T: running trace @{:}[{:}]", instr.func.name, instr.pc);
-                            let mut runner = Runner::new(self, trace);
-                            next = runner.run();
-                        }
-                        info!("T: return from trace to func {:?} pc {:?}", next.func.name, next.pc);
-                        info!("T: STACK: {:?}", self.stack);
-                        info!("T: FRAME: {:?}", self.frames.last().unwrap().locals);
-                        continue;
-                    }
+        // no trace found (for the types of the locals) => start tracing (with
+        // next instr), if loop is hot
+        if !self.hot_counters.tick(&instr.func.name, instr.pc) {
+            return Ok(Some(instr.next()));
+        }
 
-                    // no trace found => start tracing (with next instr)
-                    let res = self.trace(&next);
-                    next = res.0;
-                    traces.insert(instr.pc, res.1);
+        info!("T: tracing hot loop @{:}[{:}]", instr.func.name, instr.pc);
+        let (next, trace) = self.trace(instr);
+        match trace {
+            Ok(trace) => {
+                self.hot_counters.reset(&instr.func.name, instr.pc);
+                if !self.traces.insert(&instr.func.name, instr.pc, Rc::new(trace)) {
+                    info!("T: blacklisted loop @{:}[{:}]", instr.func.name, instr.pc);
+                    self.hot_counters.blacklist(&instr.func.name, instr.pc);
+                }
+            }
+            Err(abort) => {
+                info!("T: recording aborted: {}", abort);
+                // the loop stays hot, recording starts again the next time
+                if abort.counts() && self.hot_counters.abort(&instr.func.name, instr.pc) {
+                    info!("T: blacklisted loop @{:}[{:}]", instr.func.name, instr.pc);
                 }
+            }
+        }
+        next
+    }
 
-                Call(ref target) => {
-                    let new_func = &self.module.funcs[target];
-                    let mut frame = CallFrame::for_fn(new_func, next);
+    /// Runs `trace` for the loop at `header`, returns where the interpreter
+    /// continues, the guard which should get a bridge and whether the loop
+    /// was left. With `--validate`, the state is checked (see `validate`).
+    fn run_trace(&mut self, header: &InstrPtr, trace: &Trace) -> (InstrPtr, Option<usize>, bool) {
+        let shadow = if self.config.validate {
+            Some(Shadow::new(self, header))
+        } else {
+            None
+        };
+
+        let (next, hot_exit, left_loop, exit) = {
+            let mut runner = Runner::new(self, trace);
+            let next = runner.run();
+            (next, runner.hot_exit, runner.left_loop, runner.exit.take())
+        };
+
+        if let Some(shadow) = shadow {
+            shadow.check(self, &next, &exit.expect("trace left without exit"));
+        }
+        (next, hot_exit, left_loop)
+    }
 
-                    // pass arguments to function locals
-                    for idx in 0..frame.args_count {
-                        frame.locals[idx] = self.stack
-                            .pop()
-                            .expect("Not enough arguments passed");
-                    }
+    /// Executes an inner loop while recording: its trace runs until it leaves
+    /// the inner loop, where recording continues, and is called from the
+    /// recorded trace.
+    fn record_inner_loop(&mut self, instr: &InstrPtr) -> InstrPtr {
+        let inner = self.traces.get(&instr.func.name, instr.pc, &self.frames.last().unwrap().locals);
+        let inner = match inner {
+            Some(inner) => inner,
+            None => {
+                let abort = if self.hot_counters.is_blacklisted(&instr.func.name, instr.pc) {
+                    Abort::BlacklistedLoop
+                } else {
+                    // the inner loop gets hot (and traced) first
+                    Abort::InnerLoop
+                };
+                self.recorder.as_mut().unwrap().stop(Err(abort));
+                return instr.clone();
+            }
+        };
 
-                    self.frames.push(frame);
-                    next = InstrPtr::for_fn(new_func.clone());
-                }
+        info!("T: calling trace of inner loop @{:}[{:}]", instr.func.name, instr.pc);
+        let (exit, _, left_loop) = self.run_trace(instr, &inner);
 
-                Return => {
-                    // remove latest callframe
-                    let old_frame = self.frames
-                        .pop()
-                        .expect("Return from non existing frame.");
-
-                    // did we return from main function?
-                    if self.frames.is_empty() {
-                        break;
-                    } else {
-                        next = old_frame.back_ref;
-                    }
-                }
+        let recorder = self.recorder.as_mut().unwrap();
 
-                Jump(target) => {
-                    next = instr.jump(target);
-                }
+        // any other exit continues somewhere within the inner loop
+        if !left_loop {
+            recorder.stop(Err(Abort::InnerExit));
+            return exit;
+        }
 
-                JumpIfFalse(target) => {
-                    if let false = self.stack.pop_into::<bool>() {
-                        next = instr.jump(target);
-                    }
-                }
+        let offset = recorder.locals.current();
+        recorder.locals.reserve(offset + inner.locals_count);
+        let frames = recorder.frames();
+        recorder.emit(TraceInstruction::CallLoop(LoopCall {
+            trace: inner,
+            offset,
+            frames,
+            header: instr.clone(),
+        }));
+
+        exit
+    }
+
+    fn log_statistics(&self) {
+        for (func, pc, count) in self.hot_counters.iter() {
+            info!("H: loop @{:}[{:}] executed {:} times", func, pc, count);
+        }
 
-                _ => panic!("TODO: {:?}", instr),
+        for (func, pc, aborts) in self.hot_counters.aborts() {
+            info!("H: loop @{:}[{:}] aborted {:} times", func, pc, aborts);
+        }
+
+        for info in self.traces.iter() {
+            let guards = info.trace.guard_count();
+            let stacks = info.trace.deopt.stack_count();
+            info!("C: trace @{:}[{:}] {:?} {:} bytes, executed {:} times",
+                  info.func, info.pc, info.trace.entry_types, info.size, info.executions);
+            info!("C:     deopt metadata {:} bytes, {:} guards share {:} inline stacks",
+                  info.trace.deopt.size(), guards, stacks);
+
+            for (idx, exit) in info.trace.exits.borrow().iter() {
+                info!("C:     guard {:} failed {:} times, bridge: {:}",
+                      idx, exit.failures, exit.bridge.is_some());
             }
         }
+        info!("C: {:} traces, {:} bytes", self.traces.len(), self.traces.memory_used());
+    }
+
+    /// Pushes the frame of a call of `func` returning to `back_ref`, its
+    /// arguments are taken from the stack.
+    fn do_call(&mut self, func: &Func, back_ref: InstrPtr) {
+        let mut frame = CallFrame::for_fn(func, back_ref);
+
+        // pass arguments to function locals
+        for idx in 0..frame.args_count {
+            frame.locals[idx] = self.stack
+                .pop()
+                .expect("Not enough arguments passed");
+        }
+
+        self.frames.push(frame);
     }
 
-    fn do_add(&mut self) {
+    /// Pops the current frame, returns where the caller continues or `None`
+    /// if the program has ended.
+    fn do_return(&mut self) -> Option<InstrPtr> {
+        let old_frame = self.frames
+            .pop()
+            .expect("Return from non existing frame.");
+
+        // did we return from main function?
+        if self.frames.is_empty() {
+            return None;
+        }
+        Some(old_frame.back_ref)
+    }
+
+    fn do_add(&mut self) -> Result<(), VmError> {
         let (left, right) = self.stack.pop_2_into::<usize>();
-        self.stack.push_from(left + right);
+        self.stack.push_from(left.checked_add(right).ok_or(VmError::Overflow)?);
+        Ok(())
     }
 
     fn do_push(&mut self) {
@@ -391,7 +857,10 @@ impl<'a> Interpreter<'a> {
 
     fn do_print(&mut self) {
         if let Value::Usize(v) = self.stack.pop().unwrap() {
-            println!("{:?}", v);
+            if !self.config.quiet {
+                println!("{:?}", v);
+            }
+            self.output.push(v);
         }
     }
 
@@ -399,54 +868,339 @@ impl<'a> Interpreter<'a> {
         self.stack.push_from(Vec::with_capacity(capacity));
     }
 
-    fn do_array_get(&mut self) {
+    fn do_array_get(&mut self) -> Result<(), VmError> {
         let index: usize = self.stack.pop_into();
         let xs: Vec<usize> = self.stack.pop_into();
-        self.stack.push_from(xs[index]);
-
+        let value = *xs.get(index).ok_or(VmError::IndexOutOfBounds { index, len: xs.len() })?;
+        self.stack.push_from(value);
+        Ok(())
     }
 
     fn do_cmp(&mut self, how: Comp) {
         let (left, right) = self.stack.pop_2_into::<usize>();
-        self.stack.push_from(match how {
-            Comp::Lt => left < right,
-            Comp::Le => left <= right,
-            _ => panic!("TODO"),
-        });
+        self.stack.push_from(how.apply(left, right));
+    }
+
+    /// Error executing `instr` would raise, checked before recording it.
+    fn fails(&self, instr: &Instruction) -> Option<VmError> {
+        let stack = &self.stack;
+        let locals = &self.frames.last().unwrap().locals;
+
+        match (instr, &stack[stack.len().saturating_sub(2)..]) {
+            (&Instruction::Add, &[Value::Usize(a), Value::Usize(b)]) if a.checked_add(b).is_none() => {
+                Some(VmError::Overflow)
+            }
+            (&Instruction::IncLocal(idx, n), _) => match locals[idx] {
+                Value::Usize(a) if a.checked_add(n).is_none() => Some(VmError::Overflow),
+                _ => None,
+            },
+            (&Instruction::ArrayGet, &[Value::Array(ref xs), Value::Usize(index)]) if index >= xs.len() => {
+                Some(VmError::IndexOutOfBounds { index, len: xs.len() })
+            }
+            _ => None,
+        }
+    }
+
+    /// Same as `Load(a), Load(b), Cmp(how)`, without using the stack.
+    fn do_cmp_locals(&mut self, a: usize, b: usize, how: Comp) -> bool {
+        let locals = &self.frames.last().unwrap().locals;
+        how.apply(usize::from(locals[b].clone()), usize::from(locals[a].clone()))
+    }
+
+    fn do_inc_local(&mut self, idx: usize, n: usize) -> Result<(), VmError> {
+        let local = &mut self.frames.last_mut().unwrap().locals[idx];
+        *local = Value::from(usize::from(local.clone()).checked_add(n).ok_or(VmError::Overflow)?);
+        Ok(())
     }
 }
 
 
-fn main() {
-    use Instruction::*;
+/// Applies the bytecode rewriting passes enabled in `config`.
+pub fn prepare(module: Module, config: &Config) -> Module {
+    let module = if config.peephole {
+        peephole::optimize_module(&module)
+    } else {
+        module
+    };
+
+    if config.superinstructions {
+        superinstructions::fuse_module(&module)
+    } else {
+        module
+    }
+}
+
 
+fn main() {
     env_logger::init().unwrap();
 
-    let prog = Module {
-        funcs: btreemap!{
-            "main".into() => Func {
-                name: "main".into(),
-                args_count: 0,
-                locals_count: 0,
-                instrs: vec![Array(8), Const(9), Push, Const(3), Push, Const(4), Push, Const(5), Push, Const(6), Push, Const(1), Push, Const(3), Push, Const(2), Push, Const(4), Push, Call(String::from("min_list")), Return],
-            }.into(),
-
-            "min".into() => Func {
-                name: "min".into(),
-                args_count: 2,
-                locals_count: 0,
-                instrs: vec![Load(1), Load(0), Cmp(self::Comp::Le), JumpIfFalse(6), Load(0), Jump(8), Load(1), Jump(8), Clone, Return]
-            }.into(),
-
-            "min_list".into() => Func {
-                name: "min_list".into(),
-                args_count: 1,
-                locals_count: 3,
-                instrs: vec![Load(0), Const(0), ArrayGet, Store(1), Load(0), Len, Store(2), Const(0), Store(3), Loop, Load(2), Load(3), Cmp(Comp::Lt), JumpIfFalse(25), Load(0), Load(3), ArrayGet, Load(1), Call(String::from("min")), Store(1), Load(3), Const(1), Add, Store(3), Jump(9), Break, Load(1), Print, Return],
-            }.into(),
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(1);
         }
     };
 
-    let mut interpreter = Interpreter::new(&prog);
-    interpreter.run();
+    if std::env::args().any(|arg| arg == "bench") {
+        bench::run(&config);
+        return;
+    }
+
+    if std::env::args().any(|arg| arg == "check") {
+        if !check::run() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let prog = match programs::get(&config.program) {
+        Some(prog) => prog,
+        None => {
+            eprintln!("unknown program {:?}, choose one of {:?}", config.program, programs::NAMES);
+            std::process::exit(1);
+        }
+    };
+
+    let prog = prepare(prog, &config);
+
+    let mut interpreter = Interpreter::with_config(&prog, config);
+    if let Err(error) = interpreter.run_program() {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use kaktus::{PushPop, Stack};
+
+    use abort::Abort;
+    use bytecode::Comp;
+    use bytecode::Instruction::*;
+    use ir::Ir;
+    use recorder::{Recorder, TraceDataAllocator};
+    use recovery::FrameInfo;
+    use repr::{CallFrame, Func, InstrPtr, Type, Value};
+    use testing;
+
+    use super::{Interpreter, LoopCall, Module, Trace};
+
+    fn main_module(locals_count: usize, instrs: Vec<::bytecode::Instruction>) -> Module {
+        Module {
+            funcs: btreemap!{
+                "main".into() => Func {
+                    name: "main".into(),
+                    args_count: 0,
+                    locals_count,
+                    instrs,
+                }.into(),
+            },
+        }
+    }
+
+    /// Guards of the trace of `min_list` which failed: whether they leave the
+    /// loop, how often they failed and whether they got a bridge.
+    fn min_list_exits(flags: &[&str]) -> Vec<(bool, u32, bool)> {
+        let (_, _, trace) = testing::trace_of("min_list", flags);
+        let exits = trace.exits.borrow();
+        exits.iter()
+            .map(|(&idx, exit)| {
+                let loop_exit = match trace.trace[idx] {
+                    Ir::Guard(_, ref guard, _) | Ir::Exit(ref guard, _) => guard.loop_exit,
+                    ref op => panic!("exit at {:?}", op),
+                };
+                if let Some(ref bridge) = exit.bridge {
+                    assert!(!bridge.looping);
+                }
+                (loop_exit, exit.failures, exit.bridge.is_some())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn guards_get_bridges_once_they_fail_often_enough() {
+        let exits = min_list_exits(&["--bridge-threshold=2"]);
+        assert!(exits.iter().any(|&(_, _, bridge)| bridge));
+
+        for (loop_exit, failures, bridge) in exits {
+            // leaving the loop continues in the interpreter
+            assert_eq!(bridge, !loop_exit && failures >= 2, "{} failures", failures);
+        }
+    }
+
+    #[test]
+    fn guards_get_no_bridges_without_threshold() {
+        let exits = min_list_exits(&["--bridge-threshold=0"]);
+        assert!(exits.iter().any(|&(_, failures, _)| failures > 1));
+        assert!(exits.iter().all(|&(_, _, bridge)| !bridge));
+    }
+
+    #[test]
+    fn bridges_continue_with_the_trace() {
+        assert_eq!(testing::output("min_list", &["--bridge-threshold=1"]),
+                   testing::output("min_list", &["--no-jit"]));
+    }
+
+    /// Example program `name`, the `Loop` of its outer loop with its trace,
+    /// and the call of the inner loop trace from it.
+    fn outer_loop(name: &str) -> (Module, InstrPtr, Rc<Trace>, LoopCall) {
+        let config = testing::config(&[]);
+        let module = testing::program(name, &config);
+        let traces = testing::traces(&testing::run(&module, config));
+        assert_eq!(traces.len(), 2);
+
+        let (func, pc, trace) = traces.iter()
+            .find(|&(_, _, trace)| trace.trace.iter().any(|op| matches!(*op, Ir::CallLoop(..))))
+            .cloned()
+            .unwrap();
+        // the preamble calls the inner loop as well, see `opt/peel.rs`
+        let calls = trace.trace
+            .iter()
+            .filter_map(|op| match *op {
+                Ir::CallLoop(ref call, _) => Some(call.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let call = calls[0].clone();
+        assert!(calls.iter().all(|other| other.offset == call.offset && other.frames == call.frames));
+
+        // the inner trace is the cached one
+        assert!(traces.iter().any(|(_, _, inner)| Rc::ptr_eq(inner, &call.trace)));
+
+        let header = InstrPtr::new(module.funcs[&func].clone(), pc);
+        (module, header, trace, call)
+    }
+
+    /// Runs the outer loop of `name` from its start, returns the locals of
+    /// the loop frame once it is left.
+    fn run_outer_loop(name: &str, locals: &[Value]) -> Vec<Value> {
+        let (module, header, trace, _) = outer_loop(name);
+        let mut interpreter = Interpreter::with_config(&module, testing::config(&[]));
+        let (_, left_loop) = testing::run_trace(&mut interpreter, &header, &trace, locals);
+
+        assert!(left_loop);
+        assert_eq!(interpreter.frames.len(), 1);
+        interpreter.frames.pop().unwrap().locals
+    }
+
+    fn usizes(values: &[usize]) -> Vec<Value> {
+        values.iter().map(|n| Value::Usize(*n)).collect()
+    }
+
+    #[test]
+    fn inner_loops_in_the_loop_frame_share_its_slots() {
+        let (_, header, trace, call) = outer_loop("nested_loops");
+
+        assert_eq!(call.offset, 0);
+        assert!(Rc::ptr_eq(&call.header.func, &header.func));
+        assert_eq!(trace.deopt.frames(call.frames).len(), 1);
+    }
+
+    #[test]
+    fn inner_loops_in_inlined_calls_use_the_slots_of_their_frame() {
+        let (_, header, trace, call) = outer_loop("nested_call");
+
+        // `sum_n` is inlined after the two locals of `main`
+        assert_eq!(call.offset, header.func.locals_count);
+        assert_eq!(call.header.func.name, "sum_n");
+        assert!(trace.locals_count >= call.offset + call.trace.locals_count);
+
+        let frames = trace.deopt.frames(call.frames);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].delta as usize, call.offset);
+        assert_eq!(trace.deopt.innermost(call.frames).name, "sum_n");
+    }
+
+    #[test]
+    fn outer_loops_run_their_inner_loops() {
+        // i, j, total: adds up `i + j` for all `i` and `j` in `0..10`
+        assert_eq!(run_outer_loop("nested_loops", &usizes(&[0, 0, 0])), usizes(&[10, 10, 900]));
+        // i, total: adds up `sum_n(i)` for all `i` in `0..10`
+        assert_eq!(run_outer_loop("nested_call", &usizes(&[0, 0])), usizes(&[10, 120]));
+    }
+
+    #[test]
+    fn loops_get_a_trace_per_type_of_their_locals() {
+        let config = testing::config(&[]);
+        let module = testing::program("poly_loop", &config);
+        let mut interpreter = testing::run(&module, config);
+
+        // `keep(x, n)`, x is the second local
+        let traces = testing::traces(&interpreter);
+        let types = traces.iter()
+            .map(|(func, _, trace)| {
+                assert_eq!(func, "keep");
+                trace.entry_types.iter().find(|&&(slot, _)| slot == 1).map(|&(_, ty)| ty)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec![Some(Type::Usize), Some(Type::Array)]);
+
+        let (_, pc, _) = traces[0];
+        let locals = |x| vec![Value::Usize(10), x, Value::Usize(0), Value::Null];
+        for (x, trace) in vec![Value::Usize(7), Value::Array(vec![3, 4])].into_iter().zip(&traces) {
+            let found = interpreter.traces.get("keep", pc, &locals(x)).unwrap();
+            assert!(Rc::ptr_eq(&found, &trace.2));
+        }
+        assert!(interpreter.traces.get("keep", pc, &locals(Value::Bool(true))).is_none());
+    }
+
+    #[test]
+    fn traces_reject_locals_of_other_types() {
+        let (_, _, trace) = testing::trace_of("sum", &[]);
+        let types = trace.entry_types.iter().map(|&(_, ty)| ty).collect::<Vec<_>>();
+        assert!(types.iter().all(|&ty| ty == Type::Usize));
+
+        assert!(trace.accepts(&[Value::Usize(0), Value::Usize(0), Value::Null]));
+        assert!(!trace.accepts(&[Value::Usize(0), Value::Bool(false), Value::Null]));
+        assert!(!trace.accepts(&[Value::Array(vec![]), Value::Usize(0), Value::Null]));
+    }
+
+    #[test]
+    fn jumps_if_true_run_in_both_dispatchers() {
+        // sums up `0..10`, the loop is left with `if i >= 10 { break }`
+        let instrs = vec![Const(0), Store(0), Const(0), Store(1), Loop, Const(10), Load(0), Cmp(Comp::Ge),
+                          JumpIfTrue(18), Load(1), Load(0), Add, Store(1), Load(0), Const(1), Add, Store(0),
+                          Jump(4), Break, Load(1), Print, Return];
+
+        for flags in &[&["--no-jit"][..], &[], &["--compact"], &["--compact", "--no-peephole"]] {
+            let config = testing::config(flags);
+            let module = super::prepare(main_module(2, instrs.clone()), &config);
+            let interpreter = testing::run(&module, config);
+
+            // recording stops at `JumpIfTrue`, see `recording_stops`
+            assert!(testing::traces(&interpreter).is_empty());
+            assert_eq!(interpreter.output(), &[45], "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn recording_is_aborted_when_the_program_ends() {
+        let module = main_module(0, vec![Const(1), Return]);
+        let main = module.funcs["main"].clone();
+        let mut interpreter = Interpreter::with_config(&module, testing::config(&[]));
+        interpreter.frames.push(CallFrame::for_fn(&main, InstrPtr::new(main.clone(), 0)));
+
+        // recording as if `main` was called from a loop, which its `Return`
+        // would not leave
+        let frame = |offset| {
+            FrameInfo {
+                func: main.clone(),
+                back_ref: InstrPtr::new(main.clone(), 0),
+                offset,
+            }
+        };
+        let call_tree = Stack::root(frame(0)).push(frame(0));
+        let locals = TraceDataAllocator::for_frames(&call_tree, 0);
+        let recorder = Recorder::new(&InstrPtr::new(main.clone(), 0), call_tree, locals, 0);
+
+        let (next, trace) = interpreter.record(InstrPtr::new(main.clone(), 1), recorder);
+        assert_eq!(next.unwrap().map(|instr| instr.pc), None);
+        assert!(matches!(trace, Err(Abort::ProgramEnded)));
+        assert!(interpreter.recorder.is_none());
+    }
 }
@@ -0,0 +1,242 @@
+//! A minimal x86-64 assembler
+//!
+//! Only the handful of instructions the trace compiler needs. Memory
+//! operands always use a 32 bit displacement, jumps a 32 bit offset.
+
+/// General purpose registers, by their encoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rbx = 3,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R12 = 12,
+}
+
+/// Conditions of `setcc` and `jcc`, by their encoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    // unsigned below (carry)
+    B = 0x2,
+    AE = 0x3,
+    E = 0x4,
+    NE = 0x5,
+    BE = 0x6,
+    A = 0x7,
+}
+
+/// Position of a jump target, see `Assembler::bind`.
+#[derive(Debug, Clone, Copy)]
+pub struct Label(usize);
+
+pub struct Assembler {
+    code: Vec<u8>,
+    // position of every label, once bound
+    labels: Vec<Option<usize>>,
+    // jump offsets to patch: position of the offset and its target
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler {
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Places `label` at the next instruction.
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// The machine code, with all jumps resolved.
+    pub fn finish(mut self) -> Vec<u8> {
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label.0].expect("unbound label");
+            // offsets are relative to the end of the jump instruction
+            let offset = target as i64 - (at + 4) as i64;
+            self.code[at..at + 4].copy_from_slice(&(offset as i32).to_le_bytes());
+        }
+        self.code
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        self.rex(false, 0, reg);
+        self.code.push(0x50 + (reg as u8 & 7));
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        self.rex(false, 0, reg);
+        self.code.push(0x58 + (reg as u8 & 7));
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xc3);
+    }
+
+    /// `mov dst, src`
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.rex(true, src as u8, dst);
+        self.code.push(0x89);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    /// `mov dst, imm64`
+    pub fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        self.rex(true, 0, dst);
+        self.code.push(0xb8 + (dst as u8 & 7));
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// `mov dst, [base + disp]`
+    pub fn load(&mut self, dst: Reg, base: Reg, disp: usize) {
+        self.rex(true, dst as u8, base);
+        self.code.push(0x8b);
+        self.modrm_mem(dst as u8, base, disp);
+    }
+
+    /// `mov [base + disp], src`
+    pub fn store(&mut self, base: Reg, disp: usize, src: Reg) {
+        self.rex(true, src as u8, base);
+        self.code.push(0x89);
+        self.modrm_mem(src as u8, base, disp);
+    }
+
+    /// `mov qword [base + disp], imm32`
+    pub fn store_imm(&mut self, base: Reg, disp: usize, imm: u32) {
+        self.rex(true, 0, base);
+        self.code.push(0xc7);
+        self.modrm_mem(0, base, disp);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// `add dst, src`
+    pub fn add(&mut self, dst: Reg, src: Reg) {
+        self.rex(true, src as u8, dst);
+        self.code.push(0x01);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    /// `cmp left, right`
+    pub fn cmp(&mut self, left: Reg, right: Reg) {
+        self.rex(true, right as u8, left);
+        self.code.push(0x39);
+        self.modrm_reg(right as u8, left);
+    }
+
+    /// `test reg, reg`
+    pub fn test(&mut self, reg: Reg) {
+        self.rex(true, reg as u8, reg);
+        self.code.push(0x85);
+        self.modrm_reg(reg as u8, reg);
+    }
+
+    /// Sets `rax` to 1 if `cond` holds, to 0 otherwise (`setcc al; movzx
+    /// eax, al`).
+    pub fn set(&mut self, cond: Cond) {
+        self.code.extend_from_slice(&[0x0f, 0x90 + cond as u8, 0xc0]);
+        self.code.extend_from_slice(&[0x0f, 0xb6, 0xc0]);
+    }
+
+    /// `call reg`
+    pub fn call(&mut self, reg: Reg) {
+        self.rex(false, 0, reg);
+        self.code.push(0xff);
+        self.modrm_reg(2, reg);
+    }
+
+    pub fn jmp(&mut self, target: Label) {
+        self.code.push(0xe9);
+        self.fixup(target);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, target: Label) {
+        self.code.extend_from_slice(&[0x0f, 0x80 + cond as u8]);
+        self.fixup(target);
+    }
+
+    fn fixup(&mut self, target: Label) {
+        self.fixups.push((self.code.len(), target));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+
+    /// REX prefix, if one is needed: `wide` for 64 bit operands, `reg` is
+    /// the encoding of the register in ModRM.reg, `rm` the one in ModRM.rm
+    /// (or the opcode).
+    fn rex(&mut self, wide: bool, reg: u8, rm: Reg) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (rm as u8) >> 3;
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: Reg) {
+        self.code.push(0xc0 | (reg & 7) << 3 | (rm as u8 & 7));
+    }
+
+    fn modrm_mem(&mut self, reg: u8, base: Reg, disp: usize) {
+        assert!(disp <= i32::MAX as usize, "displacement too large");
+        self.code.push(0x80 | (reg & 7) << 3 | (base as u8 & 7));
+        // rsp and r12 as base need a SIB byte
+        if base as u8 & 7 == 4 {
+            self.code.push(0x24);
+        }
+        self.code.extend_from_slice(&(disp as u32).to_le_bytes());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Assembler, Reg};
+
+    /// Machine code emitted by `emit`.
+    fn assemble(emit: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        emit(&mut asm);
+        asm.finish()
+    }
+
+    #[test]
+    fn memory_operands_based_on_r12_have_a_sib_byte() {
+        // mov rax, [r12 + 8]
+        assert_eq!(assemble(|asm| asm.load(Reg::Rax, Reg::R12, 8)), [0x49, 0x8b, 0x84, 0x24, 8, 0, 0, 0]);
+        // mov [r12 + 16], rcx
+        assert_eq!(assemble(|asm| asm.store(Reg::R12, 16, Reg::Rcx)), [0x49, 0x89, 0x8c, 0x24, 16, 0, 0, 0]);
+        // mov rcx, [rbx + 8]
+        assert_eq!(assemble(|asm| asm.load(Reg::Rcx, Reg::Rbx, 8)), [0x48, 0x8b, 0x8b, 8, 0, 0, 0]);
+    }
+
+    #[test]
+    fn r12_needs_a_rex_prefix() {
+        assert_eq!(assemble(|asm| asm.push(Reg::R12)), [0x41, 0x54]);
+        assert_eq!(assemble(|asm| asm.pop(Reg::R12)), [0x41, 0x5c]);
+        assert_eq!(assemble(|asm| asm.push(Reg::Rbx)), [0x53]);
+        // mov r12, rdi
+        assert_eq!(assemble(|asm| asm.mov(Reg::R12, Reg::Rdi)), [0x49, 0x89, 0xfc]);
+        // mov rax, r12
+        assert_eq!(assemble(|asm| asm.mov(Reg::Rax, Reg::R12)), [0x4c, 0x89, 0xe0]);
+    }
+
+    #[test]
+    fn jumps_are_relative_to_their_end() {
+        let code = assemble(|asm| {
+            let start = asm.label();
+            let end = asm.label();
+            asm.bind(start);
+            asm.jmp(end);
+            asm.jmp(start);
+            asm.bind(end);
+        });
+        assert_eq!(code, [0xe9, 5, 0, 0, 0, 0xe9, 0xf6, 0xff, 0xff, 0xff]);
+    }
+}
@@ -0,0 +1,87 @@
+//! Executable memory
+//!
+//! Machine code is copied into pages mapped with `mmap`, which are made
+//! executable (and read-only) with `mprotect` afterwards. Only supported on
+//! x86-64 Linux, elsewhere no memory is handed out and traces run in the
+//! `Runner`.
+
+use std::fmt;
+
+
+pub struct Executable {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Executable {
+    /// Maps `code` into executable memory.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn new(code: &[u8]) -> Option<Self> {
+        use std::ptr;
+        use self::sys::*;
+
+        let len = code.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        unsafe {
+            let ptr = mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if ptr == MAP_FAILED {
+                return None;
+            }
+            let ptr = ptr as *mut u8;
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr, code.len());
+            if mprotect(ptr as *mut _, len, PROT_READ | PROT_EXEC) != 0 {
+                munmap(ptr as *mut _, len);
+                return None;
+            }
+            Some(Executable { ptr, len })
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    pub fn new(_code: &[u8]) -> Option<Self> {
+        None
+    }
+
+    pub fn ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// Number of mapped bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for Executable {
+    fn drop(&mut self) {
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        unsafe {
+            sys::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
+
+impl fmt::Debug for Executable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Executable({:?}, {} bytes)", self.ptr, self.len)
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod sys {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    pub const PAGE_SIZE: usize = 4096;
+
+    pub const PROT_READ: c_int = 1;
+    pub const PROT_WRITE: c_int = 2;
+    pub const PROT_EXEC: c_int = 4;
+    pub const MAP_PRIVATE: c_int = 2;
+    pub const MAP_ANONYMOUS: c_int = 0x20;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: c_long) -> *mut c_void;
+        pub fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
@@ -0,0 +1,346 @@
+//! Native code for traces
+//!
+//! With `--native`, looping traces are compiled to x86-64 machine code after
+//! they have been optimized. Bridges, and traces containing instructions the
+//! compiler doesn't support (allocations, inner loops), run in the `Runner`.
+//!
+//! The code keeps the values of the trace in the register file of the
+//! runner (`regalloc.rs`), in memory pointed to by `rbx`. Locals live in
+//! a table of unboxed slots pointed to by `r12`: numbers, bools and (for
+//! arrays) pointers to the `Value` in the locals of the runner. Every slot
+//! has one type for the whole trace, arrays are only read. A second half of
+//! the table flags the slots the trace has stored to, the last entry counts
+//! the iterations.
+//!
+//! Every guard jumps to a stub returning its index. The runner writes the
+//! stored slots back to its (unboxed) `Slots` and continues with the same
+//! recovery (or bridge) as if it had executed the trace itself.
+
+use repr::{Type, Value};
+use ir::{self, Ir, Repr};
+use bytecode::Comp;
+use tracerunner::Slots;
+
+use self::asm::{Assembler, Cond, Reg};
+use self::memory::Executable;
+
+use super::Trace;
+
+mod asm;
+mod memory;
+
+
+/// `registers` of the trace, `slots` as returned by `Code::unbox`; returns
+/// the index of the failed guard.
+type Entry = unsafe extern "C" fn(registers: *mut usize, slots: *mut usize) -> usize;
+
+#[derive(Debug)]
+pub struct Code {
+    memory: Executable,
+    // type of every slot used by the trace
+    slot_types: Vec<Option<Type>>,
+    // slots read before they are written, see `Trace::entry_types`
+    live_in: Vec<usize>,
+}
+
+impl Code {
+    /// Number of bytes of machine code.
+    pub fn size(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// The slot table for the `locals` of the trace, starting at `base`.
+    /// `None` if a slot the trace reads doesn't have the type the code was
+    /// compiled for.
+    pub fn unbox(&self, locals: &Slots, base: usize) -> Option<Vec<usize>> {
+        if self.live_in.iter().any(|&slot| Some(locals.ty(base + slot)) != self.slot_types[slot]) {
+            return None;
+        }
+
+        let count = self.slot_types.len();
+        let mut slots = vec![0; 2 * count + 1];
+        for (slot, ty) in self.slot_types.iter().enumerate() {
+            if *ty != Some(locals.ty(base + slot)) {
+                // written before it is read
+                continue;
+            }
+            slots[slot] = match locals.raw(base + slot) {
+                Some(raw) => raw,
+                None => locals.array(base + slot) as *const Value as usize,
+            };
+        }
+        Some(slots)
+    }
+
+    /// Runs the trace until a guard fails, returns the index of the guard.
+    ///
+    /// Unsafe, since `slots` points into the locals, which must neither be
+    /// changed nor moved meanwhile.
+    pub unsafe fn run(&self, registers: &mut [usize], slots: &mut [usize]) -> usize {
+        let entry: Entry = ::std::mem::transmute(self.memory.ptr());
+        entry(registers.as_mut_ptr(), slots.as_mut_ptr())
+    }
+
+    /// Type of `slot`, for the whole trace.
+    pub fn slot_type(&self, slot: usize) -> Type {
+        self.slot_types[slot].expect("slot not used by trace")
+    }
+
+    /// Number of iterations completed by the last run.
+    pub fn iterations(&self, slots: &[usize]) -> usize {
+        slots[2 * self.slot_types.len()]
+    }
+
+    /// Writes the slots stored by the trace back to the `locals` starting
+    /// at `base`, they stay unboxed. Arrays are never stored.
+    pub fn write_back(&self, slots: &[usize], locals: &mut Slots, base: usize) {
+        let count = self.slot_types.len();
+        for (slot, ty) in self.slot_types.iter().enumerate() {
+            if slots[count + slot] != 0 {
+                locals.store_raw(base + slot, ty.unwrap(), slots[slot]);
+            }
+        }
+    }
+}
+
+/// Compiles `trace` to machine code, `None` if it (or the platform) isn't
+/// supported.
+pub fn compile(trace: &Trace) -> Option<Code> {
+    if !trace.looping {
+        return None;
+    }
+
+    let slot_types = match slot_types(trace) {
+        Some(types) => types,
+        None => {
+            info!("T: trace not compiled, slots change their type");
+            return None;
+        }
+    };
+
+    let ir = &trace.trace;
+    let regs = &trace.registers;
+    let count = trace.locals_count;
+
+    let mut asm = Assembler::new();
+    let loop_start = asm.label();
+    let epilogue = asm.label();
+    let mut exits = Vec::new();
+
+    asm.push(Reg::Rbx);
+    asm.push(Reg::R12);
+    // keeps the stack aligned for calls
+    asm.push(Reg::Rbp);
+    asm.mov(Reg::Rbx, Reg::Rdi);
+    asm.mov(Reg::R12, Reg::Rsi);
+
+    for (pc, op) in ir.iter().enumerate() {
+        if pc == trace.loop_start {
+            // see `opt/peel.rs`
+            if pc > 0 {
+                count_iteration(&mut asm, count);
+            }
+            asm.bind(loop_start);
+        }
+
+        let dst = 8 * regs[pc];
+        match *op {
+            Ir::Const(n) => {
+                asm.mov_imm(Reg::Rax, n as u64);
+                asm.store(Reg::Rbx, dst, Reg::Rax);
+            }
+            Ir::ConstBool(b) => {
+                asm.mov_imm(Reg::Rax, b as u64);
+                asm.store(Reg::Rbx, dst, Reg::Rax);
+            }
+            Ir::Load(slot) => {
+                asm.load(Reg::Rax, Reg::R12, 8 * slot);
+                asm.store(Reg::Rbx, dst, Reg::Rax);
+            }
+            Ir::Store(slot, val) => {
+                asm.load(Reg::Rax, Reg::Rbx, 8 * regs[val]);
+                asm.store(Reg::R12, 8 * slot, Reg::Rax);
+                asm.store_imm(Reg::R12, 8 * (count + slot), 1);
+            }
+            Ir::Add(a, b) | Ir::Overflows(a, b) => {
+                asm.load(Reg::Rax, Reg::Rbx, 8 * regs[a]);
+                asm.load(Reg::Rcx, Reg::Rbx, 8 * regs[b]);
+                asm.add(Reg::Rax, Reg::Rcx);
+                if let Ir::Overflows(..) = *op {
+                    asm.set(Cond::B);
+                }
+                asm.store(Reg::Rbx, dst, Reg::Rax);
+            }
+            Ir::Cmp(how, left, right) => {
+                asm.load(Reg::Rax, Reg::Rbx, 8 * regs[left]);
+                asm.load(Reg::Rcx, Reg::Rbx, 8 * regs[right]);
+                asm.cmp(Reg::Rax, Reg::Rcx);
+                asm.set(match how {
+                    Comp::Eq => Cond::E,
+                    Comp::Lt => Cond::B,
+                    Comp::Le => Cond::BE,
+                    Comp::Gt => Cond::A,
+                    Comp::Ge => Cond::AE,
+                });
+                asm.store(Reg::Rbx, dst, Reg::Rax);
+            }
+            // arrays are read through helpers, from loaded locals only
+            Ir::Len(xs) if is_load(ir, xs) => {
+                asm.load(Reg::Rdi, Reg::Rbx, 8 * regs[xs]);
+                asm.mov_imm(Reg::Rax, array_len as *const () as u64);
+                asm.call(Reg::Rax);
+                asm.store(Reg::Rbx, dst, Reg::Rax);
+            }
+            Ir::ArrayGet(xs, index) if is_load(ir, xs) => {
+                asm.load(Reg::Rdi, Reg::Rbx, 8 * regs[xs]);
+                asm.load(Reg::Rsi, Reg::Rbx, 8 * regs[index]);
+                asm.mov_imm(Reg::Rax, array_get as *const () as u64);
+                asm.call(Reg::Rax);
+                asm.store(Reg::Rbx, dst, Reg::Rax);
+            }
+            Ir::Guard(cond, ref guard, _) => {
+                let exit = asm.label();
+                exits.push((pc, exit));
+                asm.load(Reg::Rax, Reg::Rbx, 8 * regs[cond]);
+                asm.test(Reg::Rax);
+                asm.jcc(if guard.condition { Cond::E } else { Cond::NE }, exit);
+            }
+            Ir::Exit(..) => {
+                let exit = asm.label();
+                exits.push((pc, exit));
+                asm.jmp(exit);
+            }
+            // see `Registers::value` of the runner
+            Ir::Sunk(_) |
+            Ir::Nop => (),
+
+            Ir::Len(_) | Ir::ArrayGet(..) | Ir::NewArray(_) | Ir::Push(..) |
+            Ir::Entry(_) | Ir::CallLoop(..) => {
+                info!("T: trace not compiled, unsupported {:?}", op);
+                return None;
+            }
+        }
+    }
+
+    if trace.loop_start == ir.len() {
+        asm.bind(loop_start);
+    }
+    count_iteration(&mut asm, count);
+    asm.jmp(loop_start);
+
+    for (pc, exit) in exits {
+        asm.bind(exit);
+        asm.mov_imm(Reg::Rax, pc as u64);
+        asm.jmp(epilogue);
+    }
+
+    asm.bind(epilogue);
+    asm.pop(Reg::Rbp);
+    asm.pop(Reg::R12);
+    asm.pop(Reg::Rbx);
+    asm.ret();
+
+    let code = asm.finish();
+    let memory = Executable::new(&code)?;
+    info!("T: compiled trace to {} bytes of machine code", code.len());
+
+    Some(Code {
+        memory,
+        slot_types,
+        live_in: trace.entry_types.iter().map(|&(slot, _)| slot).collect(),
+    })
+}
+
+/// Type of every slot of `trace`, `None` if a slot is stored with different
+/// types (or an array is stored).
+fn slot_types(trace: &Trace) -> Option<Vec<Option<Type>>> {
+    let ir = &trace.trace;
+    let mut types = vec![None; trace.locals_count];
+    for &(slot, ty) in &trace.entry_types {
+        types[slot] = Some(ty);
+    }
+
+    for op in ir {
+        match *op {
+            Ir::Load(slot) => {
+                types[slot]?;
+            }
+            Ir::Store(slot, val) => {
+                let ty = match ir::repr(&ir[val]) {
+                    Repr::Usize => Type::Usize,
+                    Repr::Bool => Type::Bool,
+                    Repr::Boxed => match ir[val] {
+                        Ir::Load(slot) => types[slot]?,
+                        _ => return None,
+                    },
+                    Repr::Sunk(_) => return None,
+                };
+                if ty == Type::Array || types[slot].is_some_and(|other| other != ty) {
+                    return None;
+                }
+                types[slot] = Some(ty);
+            }
+            _ => (),
+        }
+    }
+
+    Some(types)
+}
+
+/// Increments the iteration counter behind the slots.
+fn count_iteration(asm: &mut Assembler, count: usize) {
+    asm.load(Reg::Rax, Reg::R12, 8 * 2 * count);
+    asm.mov_imm(Reg::Rcx, 1);
+    asm.add(Reg::Rax, Reg::Rcx);
+    asm.store(Reg::R12, 8 * 2 * count, Reg::Rax);
+}
+
+fn is_load(ir: &[Ir], val: usize) -> bool {
+    matches!(ir[val], Ir::Load(_))
+}
+
+extern "C" fn array_len(array: *const Value) -> usize {
+    unsafe { (*array).as_array().len() }
+}
+
+// see `Ir::ArrayGet`
+extern "C" fn array_get(array: *const Value, index: usize) -> usize {
+    unsafe { (*array).as_array()[index] }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use repr::Value;
+    use testing;
+
+    /// Locals of `min_list` entering its loop with `xs`.
+    fn min_list_entry(xs: &[usize]) -> Vec<Value> {
+        vec![Value::Array(xs.to_vec()), Value::Usize(xs[0]), Value::Usize(xs.len()), Value::Usize(0)]
+    }
+
+    #[test]
+    fn min_list_is_compiled() {
+        let (_, _, trace) = testing::trace_of("min_list", &["--native"]);
+        assert!(trace.native.is_some());
+    }
+
+    #[test]
+    fn guard_exits_are_the_same_as_in_the_runner() {
+        // the guard on the new minimum fails at 4, the loop is left after 6
+        for &(xs, left_loop) in &[(&[9, 3, 4, 1][..], false), (&[9, 8, 7, 6], true)] {
+            let entry = min_list_entry(xs);
+            let native = testing::trace_exit("min_list", &["--native"], &entry);
+            let runner = testing::trace_exit("min_list", &[], &entry);
+            assert_eq!(native.2, left_loop, "{:?}", xs);
+            assert_eq!(native, runner, "{:?}", xs);
+        }
+    }
+
+    #[test]
+    fn min_list_output_is_the_same_as_without_jit() {
+        assert_eq!(testing::output("min_list", &["--native"]), testing::output("min_list", &["--no-jit"]));
+        assert_eq!(testing::output("min_list", &["--native", "--bridge-threshold=1"]),
+                   testing::output("min_list", &["--no-jit"]));
+    }
+}
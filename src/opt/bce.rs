@@ -0,0 +1,138 @@
+//! Bounds and overflow check elimination
+//!
+//! Loops over arrays are guarded by their loop condition `i < n`, and the
+//! induction variable `i` is used as index into the array `xs`. The bounds
+//! check `i < len(xs)` following the loop condition is replaced with
+//! `n <= len(xs)`, which implies it. If `n` and `xs` are loop invariant, so
+//! is the new check, and loop peeling (see `peel.rs`) hoists it out of the
+//! loop.
+//!
+//! Incrementing the induction variable by one can't overflow either, it is
+//! below `n`. Those overflow checks are folded to `false`, `fold.rs` removes
+//! their guards.
+
+use std::collections::BTreeMap;
+
+use bytecode::Comp;
+use ir::{Ir, Ref};
+use super::{written_slots, Pass};
+use Trace;
+
+
+pub struct CheckElimination;
+
+impl Pass for CheckElimination {
+    fn name(&self) -> &'static str {
+        "bce"
+    }
+
+    fn run(&self, trace: &mut Trace) -> bool {
+        let written = written_slots(&trace.trace);
+        let loop_start = trace.loop_start;
+        let ir = &mut trace.trace;
+
+        let invariant = |ir: &[Ir], val: Ref| {
+            val < loop_start || match ir[val] {
+                Ir::Const(_) => true,
+                Ir::Load(slot) => written.as_ref().is_some_and(|written| !written.contains(&slot)),
+                _ => false,
+            }
+        };
+
+        // value -> bounds it is known to be below, from guards which held
+        let mut below: BTreeMap<Ref, Vec<Ref>> = BTreeMap::new();
+        let mut changed = false;
+
+        for idx in 0..ir.len() {
+            match ir[idx] {
+                Ir::Guard(cond, ref guard, _) if guard.condition => {
+                    let (index, bound) = match ir[cond] {
+                        Ir::Cmp(Comp::Lt, index, bound) => (index, bound),
+                        _ => continue,
+                    };
+
+                    let replacement = match ir[bound] {
+                        Ir::Len(xs) if invariant(ir, xs) && only_use(ir, cond, idx) => {
+                            below.get(&index).and_then(|bounds| bounds.iter().find(|&&n| n < cond && invariant(ir, n)))
+                        }
+                        _ => None,
+                    };
+                    if let Some(&n) = replacement {
+                        ir[cond] = Ir::Cmp(Comp::Le, n, bound);
+                        changed = true;
+                    }
+
+                    below.entry(index).or_default().push(bound);
+                }
+
+                Ir::Overflows(a, b) => {
+                    let increments = |val: Ref, other: Ref| {
+                        below.contains_key(&val) && matches!(ir[other], Ir::Const(1))
+                    };
+                    if increments(a, b) || increments(b, a) {
+                        ir[idx] = Ir::ConstBool(false);
+                        changed = true;
+                    }
+                }
+
+                _ => (),
+            }
+        }
+
+        changed
+    }
+}
+
+/// Whether the guard at `guard` is the only instruction using `val`.
+fn only_use(ir: &[Ir], val: Ref, guard: usize) -> bool {
+    ir.iter().enumerate().all(|(idx, op)| idx == guard || !op.operands().contains(&val))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use bytecode::Comp;
+    use ir::Ir;
+    use testing::min_list_trace;
+
+    /// Conditions of the bounds and overflow checks in `range` of `ir`.
+    fn checks(ir: &[Ir], range: Range<usize>) -> Vec<&Ir> {
+        ir[range]
+            .iter()
+            .filter_map(|op| match *op {
+                Ir::Guard(cond, ..) => Some(&ir[cond]),
+                _ => None,
+            })
+            .filter(|cond| match **cond {
+                Ir::Cmp(_, _, bound) => matches!(ir[bound], Ir::Len(_)),
+                Ir::Overflows(..) => true,
+                _ => false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn min_list_loop_has_no_checks() {
+        let trace = min_list_trace("forward,dse,sink,bce,fold,peel,dce");
+        let ir = &trace.trace;
+
+        assert!(trace.loop_start > 0);
+        let checked = checks(ir, trace.loop_start..ir.len());
+        assert!(checked.is_empty(), "loop checks {:?}", checked);
+
+        // `n <= len(xs)` is checked once, by the preamble
+        let preamble = checks(ir, 0..trace.loop_start);
+        assert_eq!(preamble.len(), 1);
+        assert!(matches!(*preamble[0], Ir::Cmp(Comp::Le, ..)));
+    }
+
+    #[test]
+    fn min_list_loop_is_checked_without_bce() {
+        let trace = min_list_trace("forward,dse,sink,fold,peel,dce");
+        let ir = &trace.trace;
+
+        assert_eq!(checks(ir, trace.loop_start..ir.len()).len(), 2);
+    }
+}
@@ -0,0 +1,43 @@
+//! Dead code elimination
+//!
+//! Removes instructions whose value is never used. Instructions with side
+//! effects (stores, guards, calls of inner traces) and those which might fail
+//! (`ArrayGet`) always stay.
+
+use ir::Ir;
+use super::Pass;
+use Trace;
+
+
+pub struct DeadCodeElimination;
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run(&self, trace: &mut Trace) -> bool {
+        let ir = &mut trace.trace;
+        let mut used = vec![false; ir.len()];
+        let mut changed = false;
+
+        for idx in (0..ir.len()).rev() {
+            let removable = matches!(ir[idx],
+                                     Ir::Const(_) | Ir::ConstBool(_) | Ir::Load(_) | Ir::Add(..) |
+                                     Ir::Overflows(..) | Ir::Cmp(..) | Ir::Len(_) | Ir::NewArray(_) |
+                                     Ir::Push(..) | Ir::Sunk(_));
+
+            if removable && !used[idx] {
+                ir[idx] = Ir::Nop;
+                changed = true;
+                continue;
+            }
+
+            for val in ir[idx].operands() {
+                used[val] = true;
+            }
+        }
+
+        changed
+    }
+}
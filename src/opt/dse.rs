@@ -0,0 +1,105 @@
+//! Dead store elimination
+//!
+//! A store is dead, if the slot is written again before it is read. Reads are
+//! loads, calls of inner traces (which read any slot) and the start of the
+//! next iteration (`Trace::loop_start`), for slots the loop reads before
+//! writing them. Bridges
+//! continue with the trace they belong to, all slots are read at their end.
+//!
+//! Guards read slots as well: recovery rebuilds the frames of the guard from
+//! the slots, and a bridge continues with them. Instead of keeping a store
+//! only needed by guards, it is sunk into their snapshots, the stored value
+//! is written to the slot when the guard fails (see `Runner::exit`).
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ir::{Ir, Ref};
+use recovery::DeoptTable;
+use super::Pass;
+use Trace;
+
+
+pub struct DeadStoreElimination;
+
+impl Pass for DeadStoreElimination {
+    fn name(&self) -> &'static str {
+        "dse"
+    }
+
+    fn run(&self, trace: &mut Trace) -> bool {
+        let locals_count = trace.locals_count;
+        let ir = &mut trace.trace;
+        let deopt = &trace.deopt;
+
+        // slots read after the current instruction, before they are written
+        let mut live = if trace.looping {
+            live_in(&ir[trace.loop_start..], deopt, locals_count)
+        } else {
+            (0..locals_count).collect()
+        };
+        // slot -> guards after the current instruction, which need the slot
+        let mut guards: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        // stores to sink, guard -> slot and value
+        let mut sunk: Vec<(usize, usize, Ref)> = Vec::new();
+        let mut changed = false;
+
+        for idx in (0..ir.len()).rev() {
+            match ir[idx] {
+                Ir::Load(slot) => {
+                    live.insert(slot);
+                }
+                Ir::Store(slot, val) => {
+                    let needed_by = guards.remove(&slot).unwrap_or_default();
+                    if !live.remove(&slot) {
+                        sunk.extend(needed_by.into_iter().map(|guard| (guard, slot, val)));
+                        ir[idx] = Ir::Nop;
+                        changed = true;
+                    }
+                }
+                Ir::CallLoop(..) => {
+                    live.extend(0..locals_count);
+                    guards.clear();
+                }
+                Ir::Guard(_, ref guard, _) | Ir::Exit(ref guard, _) => {
+                    for slot in deopt.slots(guard.frames) {
+                        if !live.contains(&slot) {
+                            guards.entry(slot).or_default().push(idx);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for (guard, slot, val) in sunk {
+            match ir[guard] {
+                Ir::Guard(_, _, ref mut snapshot) | Ir::Exit(_, ref mut snapshot) => snapshot.slots.push((slot, val)),
+                _ => unreachable!(),
+            }
+        }
+
+        changed
+    }
+}
+
+/// Slots read by an iteration before they are written.
+fn live_in(ir: &[Ir], deopt: &DeoptTable, locals_count: usize) -> BTreeSet<usize> {
+    let mut written = BTreeSet::new();
+    let mut live = BTreeSet::new();
+
+    for op in ir {
+        let reads = match *op {
+            Ir::Load(slot) => vec![slot],
+            Ir::Store(slot, _) => {
+                written.insert(slot);
+                continue;
+            }
+            Ir::CallLoop(..) => (0..locals_count).collect(),
+            Ir::Guard(_, ref guard, _) | Ir::Exit(ref guard, _) => deopt.slots(guard.frames),
+            _ => continue,
+        };
+        live.extend(reads.into_iter().filter(|slot| !written.contains(slot)));
+    }
+
+    live
+}
@@ -0,0 +1,144 @@
+//! Constant propagation and folding
+//!
+//! Constants stored into slots are propagated to later loads of the slot in
+//! the same iteration (calls of inner traces might write any slot, and the
+//! loop of a peeled trace is entered from its end as well). `Add` and
+//! `Cmp` of constants are folded, as are additions of `0`.
+//!
+//! Guards on constant conditions are folded as well: a guard which always
+//! holds is removed, one which always fails becomes an unconditional `Exit`.
+//! Everything after an `Exit` is unreachable and removed.
+
+use std::collections::BTreeMap;
+
+use ir::{Ir, Ref};
+use super::Pass;
+use Trace;
+
+
+pub struct ConstantFolding;
+
+#[derive(Clone, Copy)]
+enum Constant {
+    Usize(usize),
+    Bool(bool),
+}
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(&self, trace: &mut Trace) -> bool {
+        let ir = &mut trace.trace;
+        let mut constants = vec![None; ir.len()];
+        // replacements for values of removed instructions
+        let mut subst = (0..ir.len()).collect::<Vec<Ref>>();
+        // slot -> constant stored into it
+        let mut slots = BTreeMap::new();
+        let mut changed = false;
+
+        for idx in 0..ir.len() {
+            ir[idx].map_operands(|val| subst[val]);
+            if idx == trace.loop_start {
+                slots.clear();
+            }
+
+            let folded = match ir[idx] {
+                Ir::Const(n) => {
+                    constants[idx] = Some(Constant::Usize(n));
+                    None
+                }
+                Ir::ConstBool(b) => {
+                    constants[idx] = Some(Constant::Bool(b));
+                    None
+                }
+
+                Ir::Load(slot) => {
+                    if let Some(&val) = slots.get(&slot) {
+                        subst[idx] = val;
+                        Some(Ir::Nop)
+                    } else {
+                        None
+                    }
+                }
+                Ir::Store(slot, val) => {
+                    if constants[val].is_some() {
+                        slots.insert(slot, val);
+                    } else {
+                        slots.remove(&slot);
+                    }
+                    None
+                }
+                Ir::CallLoop(..) => {
+                    slots.clear();
+                    None
+                }
+
+                Ir::Add(a, b) => {
+                    match (constants[a], constants[b]) {
+                        (Some(Constant::Usize(x)), Some(Constant::Usize(y))) => {
+                            // overflowing additions are left to the runner
+                            x.checked_add(y).map(Ir::Const)
+                        }
+                        (Some(Constant::Usize(0)), _) => {
+                            subst[idx] = b;
+                            Some(Ir::Nop)
+                        }
+                        (_, Some(Constant::Usize(0))) => {
+                            subst[idx] = a;
+                            Some(Ir::Nop)
+                        }
+                        _ => None,
+                    }
+                }
+                Ir::Overflows(a, b) => {
+                    match (constants[a], constants[b]) {
+                        (Some(Constant::Usize(x)), Some(Constant::Usize(y))) => {
+                            Some(Ir::ConstBool(x.checked_add(y).is_none()))
+                        }
+                        _ => None,
+                    }
+                }
+                Ir::Cmp(how, left, right) => {
+                    match (constants[left], constants[right]) {
+                        (Some(Constant::Usize(x)), Some(Constant::Usize(y))) => {
+                            Some(Ir::ConstBool(how.apply(x, y)))
+                        }
+                        _ => None,
+                    }
+                }
+
+                Ir::Guard(cond, ref guard, ref snapshot) => {
+                    match constants[cond] {
+                        Some(Constant::Bool(b)) if b == guard.condition => Some(Ir::Nop),
+                        Some(Constant::Bool(_)) => Some(Ir::Exit(guard.clone(), snapshot.clone())),
+                        _ => None,
+                    }
+                }
+
+                Ir::NewArray(_) | Ir::Push(..) | Ir::ArrayGet(..) | Ir::Len(_) | Ir::Sunk(_) | Ir::Entry(_) | Ir::Exit(..) | Ir::Nop => None,
+            };
+
+            if let Some(op) = folded {
+                match op {
+                    Ir::Const(n) => constants[idx] = Some(Constant::Usize(n)),
+                    Ir::ConstBool(b) => constants[idx] = Some(Constant::Bool(b)),
+                    _ => (),
+                }
+                ir[idx] = op;
+                changed = true;
+            }
+
+            if let Ir::Exit(..) = ir[idx] {
+                for op in &mut ir[idx + 1..] {
+                    changed |= !matches!(*op, Ir::Nop);
+                    *op = Ir::Nop;
+                }
+                break;
+            }
+        }
+
+        changed
+    }
+}
@@ -0,0 +1,58 @@
+//! Load forwarding
+//!
+//! Within an iteration, a load of a slot is replaced with the value last
+//! stored into (or loaded from) the slot. This removes the loads of call
+//! arguments in inlined frames, which follow their stores immediately, and
+//! repeated loads of the same local. Calls of inner traces might write any
+//! slot, nothing is forwarded across them. Neither is anything forwarded
+//! from the preamble of a peeled trace into the loop, which is also entered
+//! from its own end.
+
+use std::collections::BTreeMap;
+
+use ir::{Ir, Ref};
+use super::Pass;
+use Trace;
+
+
+pub struct LoadForwarding;
+
+impl Pass for LoadForwarding {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    fn run(&self, trace: &mut Trace) -> bool {
+        let ir = &mut trace.trace;
+        let mut subst = (0..ir.len()).collect::<Vec<Ref>>();
+        // slot -> value it currently holds
+        let mut slots = BTreeMap::new();
+        let mut changed = false;
+
+        for idx in 0..ir.len() {
+            ir[idx].map_operands(|val| subst[val]);
+            if idx == trace.loop_start {
+                slots.clear();
+            }
+
+            match ir[idx] {
+                Ir::Load(slot) => {
+                    if let Some(&val) = slots.get(&slot) {
+                        subst[idx] = val;
+                        ir[idx] = Ir::Nop;
+                        changed = true;
+                    } else {
+                        slots.insert(slot, idx);
+                    }
+                }
+                Ir::Store(slot, val) => {
+                    slots.insert(slot, val);
+                }
+                Ir::CallLoop(..) => slots.clear(),
+                _ => (),
+            }
+        }
+
+        changed
+    }
+}
@@ -0,0 +1,126 @@
+//! Trace optimizer
+//!
+//! Recorded traces (and bridges) run through a pipeline of passes over the
+//! IR (see `ir.rs`), before they are cached. Which passes run, and in which
+//! order, is configured with `--passes=<name>,...`, single passes can be
+//! disabled with `--disable-pass=<name>`.
+//!
+//! Passes replace removed instructions with `Ir::Nop`, the optimizer
+//! compacts the trace afterwards and verifies it (`ir::verify`) before the
+//! next pass runs. With logging enabled, the trace is dumped before and after
+//! every pass which changed it. Finally, registers are allocated for the
+//! values of the trace (see `regalloc.rs`).
+//!
+//! The frames guards recover are encoded while recording (see
+//! `recovery::DeoptTable`), passes only see their index.
+
+use std::collections::BTreeSet;
+
+use config::Config;
+use ir::{self, Ir};
+use regalloc;
+
+use super::Trace;
+
+mod bce;
+mod dce;
+mod dse;
+mod fold;
+mod forward;
+mod peel;
+mod sink;
+
+
+/// All passes, in default order.
+pub const NAMES: &[&str] = &["forward", "dse", "sink", "bce", "fold", "peel", "dce"];
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    /// Rewrites `trace`, returns whether anything changed.
+    fn run(&self, trace: &mut Trace) -> bool;
+}
+
+fn pass(name: &str) -> Option<Box<dyn Pass>> {
+    match name {
+        "forward" => Some(Box::new(forward::LoadForwarding)),
+        "bce" => Some(Box::new(bce::CheckElimination)),
+        "fold" => Some(Box::new(fold::ConstantFolding)),
+        "dse" => Some(Box::new(dse::DeadStoreElimination)),
+        "sink" => Some(Box::new(sink::AllocationSinking)),
+        "peel" => Some(Box::new(peel::LoopPeeling)),
+        "dce" => Some(Box::new(dce::DeadCodeElimination)),
+        _ => None,
+    }
+}
+
+
+pub struct Optimizer {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Optimizer {
+    pub fn new(config: &Config) -> Self {
+        let passes = config.passes
+            .iter()
+            .filter(|name| !config.disabled_passes.contains(name))
+            .map(|name| pass(name).unwrap_or_else(|| panic!("unknown pass {:?}", name)))
+            .collect();
+
+        Optimizer {
+            passes,
+        }
+    }
+
+    pub fn run(&self, trace: &mut Trace) {
+        verify(trace, "recording");
+
+        for pass in &self.passes {
+            let before = if log_enabled!(::log::LogLevel::Info) {
+                ir::dump(&trace.trace)
+            } else {
+                String::new()
+            };
+
+            if !pass.run(trace) {
+                continue;
+            }
+
+            ir::compact(&mut trace.trace, &mut trace.loop_start);
+            verify(trace, pass.name());
+
+            info!("O: {} changed trace, before:\n{}after:\n{}", pass.name(), before, ir::dump(&trace.trace));
+        }
+
+        regalloc::allocate(trace);
+
+        let written = written_slots(&trace.trace);
+        trace.read_only = (0..trace.locals_count)
+            .map(|slot| written.as_ref().is_some_and(|written| !written.contains(&slot)))
+            .collect();
+
+        info!("O: {} guards share {} inline stacks, {} bytes of deopt metadata",
+              trace.guard_count(), trace.deopt.stack_count(), trace.deopt.size());
+    }
+}
+
+fn verify(trace: &Trace, after: &str) {
+    if let Err(error) = ir::verify(&trace.trace, trace.locals_count) {
+        panic!("invalid trace after {}: {}\n{}", after, error, ir::dump(&trace.trace));
+    }
+}
+
+/// Slots written by the trace, `None` if it calls inner traces.
+fn written_slots(ir: &[Ir]) -> Option<BTreeSet<usize>> {
+    let mut written = BTreeSet::new();
+    for op in ir {
+        match *op {
+            Ir::Store(slot, _) => {
+                written.insert(slot);
+            }
+            Ir::CallLoop(..) => return None,
+            _ => (),
+        }
+    }
+    Some(written)
+}
@@ -0,0 +1,136 @@
+//! Loop peeling and loop-invariant code motion
+//!
+//! The first iteration of a trace is peeled off into a preamble, the trace
+//! loops back to the copy of the body following it (`Trace::loop_start`).
+//! Invariant computations in the loop reuse the values of the preamble
+//! instead of recomputing them, and guards on invariant conditions are
+//! dropped from the loop, they already held in the preamble.
+//!
+//! The preamble is an iteration of its own: entering the loop from it counts
+//! as an iteration, just like looping back (see `Runner::iterations`).
+//!
+//! Invariant are constants, loads of slots the trace never writes and pure
+//! instructions on invariant values. Calls of inner traces might write any
+//! slot, loads in traces with calls are never invariant.
+
+use ir::{Ir, Ref};
+use super::{written_slots, Pass};
+use Trace;
+
+
+pub struct LoopPeeling;
+
+impl Pass for LoopPeeling {
+    fn name(&self) -> &'static str {
+        "peel"
+    }
+
+    fn run(&self, trace: &mut Trace) -> bool {
+        // bridges don't loop, peeled traces already have a preamble
+        if !trace.looping || trace.loop_start > 0 {
+            return false;
+        }
+
+        let body = trace.trace.clone();
+        let written = written_slots(&body);
+
+        // the preamble is the body itself, the instruction at `idx` of the
+        // body is `idx` in the preamble and `copies[idx]` in the loop
+        let mut ir = body.clone();
+        let mut copies: Vec<Ref> = Vec::with_capacity(body.len());
+        let mut hoisted = false;
+
+        for (idx, op) in body.iter().enumerate() {
+            let mut op = op.clone();
+            op.map_operands(|val| copies[val]);
+
+            let invariant = match op {
+                Ir::Const(_) | Ir::ConstBool(_) | Ir::NewArray(_) => true,
+                Ir::Load(slot) => written.as_ref().is_some_and(|written| !written.contains(&slot)),
+                Ir::Add(..) | Ir::Overflows(..) | Ir::Cmp(..) | Ir::Push(..) | Ir::ArrayGet(..) | Ir::Len(_) |
+                Ir::Sunk(_) => op.operands().iter().all(|&val| val < body.len()),
+                Ir::Guard(cond, ..) => cond < body.len(),
+                _ => false,
+            };
+
+            if invariant {
+                // guards are not copied, for values the copy is the original
+                copies.push(idx);
+                hoisted = true;
+            } else {
+                copies.push(ir.len());
+                ir.push(op);
+            }
+        }
+
+        // without anything to hoist, peeling only doubles the trace
+        if !hoisted {
+            return false;
+        }
+
+        info!("O: peeled trace, loop starts at {:04}", body.len());
+        trace.loop_start = body.len();
+        trace.trace = ir;
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ir::Ir;
+    use testing::{self, min_list_trace};
+
+    const PASSES: &str = "forward,dse,sink,bce,fold,peel,dce";
+
+    #[test]
+    fn min_list_invariants_are_hoisted() {
+        let trace = min_list_trace(PASSES);
+        let (preamble, body) = trace.trace.split_at(trace.loop_start);
+        assert!(!preamble.is_empty());
+
+        // the list and its length are loaded once, by the preamble
+        let is_invariant = |op: &Ir| matches!(*op, Ir::Load(0) | Ir::Load(2) | Ir::Len(_) | Ir::Const(_));
+        assert!(!body.iter().any(&is_invariant), "loop {:?}", body);
+        assert_eq!(preamble.iter().filter(|op| is_invariant(op)).count(), 4);
+
+        // the loop indexes the list of the preamble
+        let lists = body.iter()
+            .filter_map(|op| match *op {
+                Ir::ArrayGet(xs, _) => Some(xs),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lists.len(), 1);
+        assert!(matches!(trace.trace[lists[0]], Ir::Load(0)));
+        assert!(lists[0] < trace.loop_start);
+
+        // the index and the minimum are written by the loop, it loads them
+        assert!(body.iter().any(|op| matches!(*op, Ir::Load(1))));
+        assert!(body.iter().any(|op| matches!(*op, Ir::Load(3))));
+    }
+
+    #[test]
+    fn min_list_is_not_peeled_without_peel() {
+        let peeled = min_list_trace(PASSES);
+        let trace = min_list_trace("forward,dse,sink,bce,fold,dce");
+
+        assert_eq!(trace.loop_start, 0);
+        // the trace without peeling is the preamble
+        assert_eq!(format!("{:?}", trace.trace), format!("{:?}", &peeled.trace[..peeled.loop_start]));
+    }
+
+    #[test]
+    fn bridges_are_not_peeled() {
+        let flags = [&format!("--passes={}", PASSES)[..], "--bridge-threshold=1"];
+        let (_, _, trace) = testing::trace_of("min_list", &flags);
+        let exits = trace.exits.borrow();
+
+        let bridges = exits.values().filter_map(|exit| exit.bridge.as_ref()).collect::<Vec<_>>();
+        assert!(!bridges.is_empty());
+        for bridge in bridges {
+            assert!(!bridge.looping);
+            assert_eq!(bridge.loop_start, 0);
+        }
+    }
+}
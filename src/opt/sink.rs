@@ -0,0 +1,190 @@
+//! Allocation sinking
+//!
+//! Arrays are values, every `Push` defines a new one. An array which does
+//! not escape the trace is not allocated at all: its length and elements
+//! are known from the `Push`es defining it, `Len` and `ArrayGet` with a
+//! constant index use them directly.
+//!
+//! Arrays escape if they are stored into a slot, indexed with an unknown
+//! index or used by anything else. Snapshots don't count, the allocation is
+//! sunk into them: the array becomes `Ir::Sunk` and is only materialized
+//! when the trace is left (see `Runner::recover`).
+
+use ir::{Ir, Ref};
+use super::Pass;
+use Trace;
+
+
+pub struct AllocationSinking;
+
+impl Pass for AllocationSinking {
+    fn name(&self) -> &'static str {
+        "sink"
+    }
+
+    fn run(&self, trace: &mut Trace) -> bool {
+        let ir = &mut trace.trace;
+        let escapes = escaping(ir);
+
+        // elements of sunk arrays
+        let mut sunk: Vec<Option<Vec<Ref>>> = vec![None; ir.len()];
+        let mut subst = (0..ir.len()).collect::<Vec<Ref>>();
+        let mut changed = false;
+
+        for idx in 0..ir.len() {
+            ir[idx].map_operands(|val| subst[val]);
+
+            let elements = match ir[idx] {
+                Ir::NewArray(_) if !escapes[idx] => vec![],
+                Ir::Push(xs, val) if !escapes[idx] => {
+                    let mut elements = sunk[xs].clone().unwrap();
+                    elements.push(val);
+                    elements
+                }
+
+                Ir::Len(xs) => {
+                    if let Some(ref elements) = sunk[xs] {
+                        ir[idx] = Ir::Const(elements.len());
+                    }
+                    continue;
+                }
+                Ir::ArrayGet(xs, index) => {
+                    if let (Some(ref elements), Ir::Const(index)) = (&sunk[xs], &ir[index]) {
+                        subst[idx] = elements[*index];
+                        ir[idx] = Ir::Nop;
+                    }
+                    continue;
+                }
+
+                _ => continue,
+            };
+
+            ir[idx] = Ir::Sunk(elements.clone());
+            sunk[idx] = Some(elements);
+            changed = true;
+        }
+
+        changed
+    }
+}
+
+/// Which arrays escape, for all instructions defining arrays.
+fn escaping(ir: &[Ir]) -> Vec<bool> {
+    // number of elements of arrays which might be sunk
+    let mut lengths: Vec<Option<usize>> = vec![None; ir.len()];
+    let mut escapes = vec![false; ir.len()];
+
+    for (idx, op) in ir.iter().enumerate() {
+        match *op {
+            Ir::NewArray(_) => lengths[idx] = Some(0),
+            Ir::Push(xs, _) => lengths[idx] = lengths[xs].map(|len| len + 1),
+            _ => (),
+        }
+
+        let uses: Vec<Ref> = match *op {
+            Ir::Push(_, val) => vec![val],
+            Ir::Len(_) => vec![],
+            Ir::ArrayGet(xs, index) => {
+                let known = match (lengths[xs], &ir[index]) {
+                    (Some(len), &Ir::Const(index)) => index < len,
+                    _ => false,
+                };
+                if known { vec![index] } else { vec![xs, index] }
+            }
+            Ir::Guard(cond, ..) => vec![cond],
+            Ir::Exit(..) | Ir::CallLoop(..) => vec![],
+            ref op => op.operands(),
+        };
+        for val in uses {
+            escapes[val] = true;
+        }
+    }
+
+    // arrays pushed to have to exist, if the result of the push escapes
+    for idx in (0..ir.len()).rev() {
+        if let Ir::Push(xs, _) = ir[idx] {
+            escapes[xs] |= escapes[idx];
+        }
+    }
+
+    // only arrays pushed to, which are sunk themselves, can be sunk
+    for idx in 0..ir.len() {
+        escapes[idx] |= lengths[idx].is_none();
+        if let Ir::Push(xs, _) = ir[idx] {
+            escapes[idx] |= escapes[xs];
+        }
+    }
+    escapes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ir::Ir;
+    use opt::Pass;
+    use recovery::DeoptTable;
+    use testing;
+    use Trace;
+
+    use super::AllocationSinking;
+
+    /// `ir` after sinking and whether anything was sunk.
+    fn sunk(ir: Vec<Ir>) -> (bool, Vec<Ir>) {
+        let mut trace = Trace::new(ir, 1, DeoptTable::default());
+        let changed = AllocationSinking.run(&mut trace);
+        (changed, trace.trace)
+    }
+
+    /// Whether `ir` allocates arrays.
+    fn allocates(ir: &[Ir]) -> bool {
+        ir.iter().any(|op| matches!(*op, Ir::NewArray(_) | Ir::Push(..)))
+    }
+
+    #[test]
+    fn array_sink_is_not_allocated() {
+        let (_, _, trace) = testing::trace_of("array_sink", &[]);
+        let ir = &trace.trace;
+        assert!(!allocates(ir), "trace {:?}", ir);
+        assert!(!ir.iter().any(|op| matches!(*op, Ir::Len(_) | Ir::ArrayGet(..))));
+
+        // the array of `pair` is only materialized when leaving the trace
+        let arrays = ir.iter()
+            .filter_map(|op| match *op {
+                Ir::Guard(_, _, ref snapshot) => Some(snapshot),
+                _ => None,
+            })
+            .flat_map(|snapshot| snapshot.slots.iter())
+            .filter(|&&(_, val)| matches!(ir[val], Ir::Sunk(_)))
+            .count();
+        assert!(arrays > 0);
+    }
+
+    #[test]
+    fn array_sink_is_allocated_without_sink() {
+        let (_, _, trace) = testing::trace_of("array_sink", &["--passes=forward,dse,bce,fold,peel,dce"]);
+        assert!(allocates(&trace.trace));
+        assert!(!trace.trace.iter().any(|op| matches!(*op, Ir::Sunk(_))));
+    }
+
+    #[test]
+    fn lengths_of_sunk_arrays_are_constants() {
+        let (changed, ir) = sunk(vec![Ir::NewArray(1), Ir::Const(7), Ir::Push(0, 1), Ir::Len(2), Ir::Store(0, 3)]);
+        assert!(changed);
+        assert!(matches!(ir[2], Ir::Sunk(ref elements) if *elements == [1]));
+        assert!(matches!(ir[3], Ir::Const(1)));
+    }
+
+    #[test]
+    fn stored_arrays_escape() {
+        let (changed, ir) = sunk(vec![Ir::NewArray(1), Ir::Const(7), Ir::Push(0, 1), Ir::Store(0, 2)]);
+        assert!(!changed);
+        assert!(matches!(ir[2], Ir::Push(0, 1)));
+    }
+
+    #[test]
+    fn arrays_indexed_with_unknown_indices_escape() {
+        let (changed, _) = sunk(vec![Ir::NewArray(1), Ir::Const(7), Ir::Push(0, 1), Ir::Load(0), Ir::ArrayGet(2, 3),
+                                     Ir::Store(0, 4)]);
+        assert!(!changed);
+    }
+}
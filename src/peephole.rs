@@ -0,0 +1,386 @@
+//! Peephole optimizer for bytecode
+//!
+//! Runs over `Func::instrs` before execution and repeats the following
+//! rewrites until nothing changes anymore:
+//!
+//!     * jump threading: jumps to unconditional jumps go to the final target,
+//!       jumps to the next instruction are removed
+//!     * constant folding: `Const, Const, Add` and comparisons of constants
+//!       followed by a conditional jump
+//!     * store/load collapsing: `Store(x), Load(x)` where `x` is not read
+//!       afterwards keeps the value on the stack, `Load(x), Store(x)` is a noop
+//!     * dead-code removal of instructions which can't be reached, except
+//!       for `Loop` and `Break` markers
+//!
+//! Removed instructions are first replaced with `None`, so that pcs stay
+//! stable while rewriting. At the end the function is compacted and all
+//! jump targets are remapped. A jump to a removed instruction continues with
+//! the next surviving one.
+//!
+//! Rewrites never span a jump target, which also means they never span a
+//! `Loop` marker (it is the target of the backwards jump) or a `Break`.
+//! Since traces are recorded on the optimized functions, the pcs stored in
+//! guards refer to the optimized code as well.
+
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+use bytecode::{self, Instruction};
+use repr::Func;
+
+use super::Module;
+
+use bytecode::Instruction::*;
+
+
+pub type Code = Vec<Option<Instruction>>;
+
+
+pub fn optimize_module(module: &Module) -> Module {
+    Module {
+        funcs: module.funcs
+            .iter()
+            .map(|(name, func)| (name.clone(), Rc::new(optimize(func))))
+            .collect(),
+    }
+}
+
+pub fn optimize(func: &Func) -> Func {
+    let mut code: Code = func.instrs.iter().cloned().map(Some).collect();
+    let locals = func.args_count + func.locals_count;
+
+    loop {
+        let mut changed = false;
+        changed |= thread_jumps(&mut code);
+        changed |= fold_constants(&mut code);
+        changed |= collapse_store_load(&mut code, locals);
+        changed |= remove_dead_code(&mut code);
+
+        if !changed {
+            break;
+        }
+    }
+
+    let instrs = compact(&code);
+    info!(target: "peephole", "{}: {:?} -> {:?}", func.name, func.instrs, instrs);
+
+    Func {
+        name: func.name.clone(),
+        args_count: func.args_count,
+        locals_count: func.locals_count,
+        instrs,
+    }
+}
+
+
+fn thread_jumps(code: &mut Code) -> bool {
+    let mut changed = false;
+
+    for pc in 0..code.len() {
+        let target = match code[pc].as_ref().and_then(Instruction::jump_target) {
+            Some(target) => target,
+            None => continue,
+        };
+
+        // removed instructions continue with the next surviving one
+        let final_target = bytecode::follow_jumps(target, code.len(), |pc| code.get(resolve(code, pc)).and_then(Option::as_ref));
+        if let Some(Jump(_)) = code[pc] {
+            if resolve(code, final_target) == resolve(code, pc + 1) {
+                code[pc] = None;
+                changed = true;
+                continue;
+            }
+        }
+
+        if final_target != target {
+            code[pc] = code[pc].as_ref().map(|instr| instr.with_jump_target(final_target));
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+fn fold_constants(code: &mut Code) -> bool {
+    let targets = jump_targets(code);
+    let pcs = live_pcs(code);
+
+    for (idx, window) in pcs.windows(3).enumerate() {
+        if window[1..].iter().any(|pc| targets.contains(pc)) {
+            continue;
+        }
+
+        match (&code[window[0]], &code[window[1]], &code[window[2]]) {
+            // `Add` is commutative, operand order does not matter
+            (&Some(Const(a)), &Some(Const(b)), &Some(Add)) if a.checked_add(b).is_some() => {
+                code[window[0]] = None;
+                code[window[1]] = None;
+                code[window[2]] = Some(Const(a + b));
+                return true;
+            }
+
+            (&Some(Const(a)), &Some(Const(b)), &Some(Cmp(how))) => {
+                let next = match pcs.get(idx + 3) {
+                    Some(next) if !targets.contains(next) => *next,
+                    _ => continue,
+                };
+
+                let target = match code[next] {
+                    Some(JumpIfFalse(target)) => target,
+                    _ => continue,
+                };
+
+                // `Cmp` takes the top of the stack as left operand
+                code[window[0]] = None;
+                code[window[1]] = None;
+                code[window[2]] = None;
+                code[next] = if how.apply(b, a) { None } else { Some(Jump(target)) };
+                return true;
+            }
+
+            _ => (),
+        }
+    }
+
+    false
+}
+
+fn collapse_store_load(code: &mut Code, locals: usize) -> bool {
+    let targets = jump_targets(code);
+    let live = live_locals(code, locals);
+    let pcs = live_pcs(code);
+
+    for window in pcs.windows(2) {
+        if targets.contains(&window[1]) {
+            continue;
+        }
+
+        let collapse = match (&code[window[0]], &code[window[1]]) {
+            (&Some(Store(a)), &Some(Load(b))) => a == b && !live[window[1] + 1].contains(&a),
+            (&Some(Load(a)), &Some(Store(b))) => a == b,
+            _ => false,
+        };
+
+        if collapse {
+            code[window[0]] = None;
+            code[window[1]] = None;
+            return true;
+        }
+    }
+
+    false
+}
+
+/// `Loop` and `Break` markers stay, even if they can't be reached: loops are
+/// matched by pairing them (see `Func::loop_end`).
+fn remove_dead_code(code: &mut Code) -> bool {
+    let mut reachable = vec![false; code.len()];
+    let mut todo = vec![0];
+
+    while let Some(pc) = todo.pop() {
+        let pc = resolve(code, pc);
+        if pc >= code.len() || reachable[pc] {
+            continue;
+        }
+        reachable[pc] = true;
+        todo.extend(successors(code, pc));
+    }
+
+    let mut changed = false;
+    for (pc, instr) in code.iter_mut().enumerate() {
+        let marker = matches!(*instr, Some(Loop) | Some(Break));
+        if !reachable[pc] && instr.is_some() && !marker {
+            *instr = None;
+            changed = true;
+        }
+    }
+    changed
+}
+
+
+fn successors(code: &Code, pc: usize) -> Vec<usize> {
+    match code[pc] {
+        Some(Jump(target)) => vec![target],
+        Some(Return) => vec![],
+        Some(ref instr) => match instr.jump_target() {
+            Some(target) => vec![target, pc + 1],
+            None => vec![pc + 1],
+        },
+        None => vec![pc + 1],
+    }
+}
+
+/// Locals which might be read before they are written again, before every pc.
+fn live_locals(code: &Code, locals: usize) -> Vec<BTreeSet<usize>> {
+    let mut live = vec![BTreeSet::new(); code.len() + 1];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for pc in (0..code.len()).rev() {
+            let mut set = BTreeSet::new();
+            if code[pc].is_none() {
+                set = live[pc + 1].clone();
+            } else {
+                for succ in successors(code, pc) {
+                    set.extend(live[succ.min(code.len())].iter().cloned());
+                }
+            }
+
+            match code[pc] {
+                Some(Load(idx)) => {
+                    set.insert(idx);
+                }
+                Some(Store(idx)) => {
+                    set.remove(&idx);
+                }
+                Some(CmpLocalsJumpIfFalse(a, b, _, _)) => {
+                    set.insert(a);
+                    set.insert(b);
+                }
+                Some(IncLocal(idx, _)) => {
+                    set.insert(idx);
+                }
+                _ => (),
+            }
+
+            debug_assert!(set.iter().all(|idx| *idx < locals));
+            if set != live[pc] {
+                live[pc] = set;
+                changed = true;
+            }
+        }
+    }
+
+    live
+}
+
+pub fn jump_targets(code: &Code) -> BTreeSet<usize> {
+    code.iter()
+        .filter_map(|instr| instr.as_ref().and_then(Instruction::jump_target))
+        .map(|target| resolve(code, target))
+        .collect()
+}
+
+pub fn live_pcs(code: &Code) -> Vec<usize> {
+    (0..code.len()).filter(|pc| code[*pc].is_some()).collect()
+}
+
+/// First surviving instruction at or after `pc`.
+pub fn resolve(code: &Code, mut pc: usize) -> usize {
+    while pc < code.len() && code[pc].is_none() {
+        pc += 1;
+    }
+    pc
+}
+
+pub fn compact(code: &Code) -> Vec<Instruction> {
+    // new pc of every old pc, removed instructions map to the next survivor
+    let mut new_pcs = Vec::with_capacity(code.len() + 1);
+    let mut count = 0;
+    for instr in code {
+        new_pcs.push(count);
+        if instr.is_some() {
+            count += 1;
+        }
+    }
+    new_pcs.push(count);
+
+    code.iter()
+        .filter_map(|instr| instr.clone())
+        .map(|instr| match instr.jump_target() {
+            Some(target) => instr.with_jump_target(new_pcs[target]),
+            None => instr,
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytecode::{Comp, Instruction};
+    use bytecode::Instruction::*;
+    use repr::Func;
+
+    use super::{collapse_store_load, fold_constants, optimize, remove_dead_code, thread_jumps, Code};
+
+    fn code(instrs: &[Instruction]) -> Code {
+        instrs.iter().cloned().map(Some).collect()
+    }
+
+    #[test]
+    fn jumps_to_jumps_go_to_the_final_target() {
+        let mut before = code(&[JumpIfFalse(2), Const(1), Jump(4), Const(2), Return]);
+        assert!(thread_jumps(&mut before));
+        assert_eq!(before, code(&[JumpIfFalse(4), Const(1), Jump(4), Const(2), Return]));
+    }
+
+    #[test]
+    fn jumps_to_the_next_instruction_are_removed() {
+        let mut before = code(&[Const(1), Jump(2), Return]);
+        assert!(thread_jumps(&mut before));
+        assert_eq!(before, vec![Some(Const(1)), None, Some(Return)]);
+    }
+
+    #[test]
+    fn constant_additions_are_folded() {
+        let mut before = code(&[Const(2), Const(3), Add, Return]);
+        assert!(fold_constants(&mut before));
+        assert_eq!(before, vec![None, None, Some(Const(5)), Some(Return)]);
+    }
+
+    #[test]
+    fn constant_conditions_become_jumps() {
+        // `2 < 1` does not hold, the jump is always taken
+        let mut before = code(&[Const(1), Const(2), Cmp(Comp::Lt), JumpIfFalse(5), Const(7), Return]);
+        assert!(fold_constants(&mut before));
+        assert_eq!(before, vec![None, None, None, Some(Jump(5)), Some(Const(7)), Some(Return)]);
+    }
+
+    #[test]
+    fn folding_does_not_span_jump_targets() {
+        let mut before = code(&[JumpIfFalse(2), Const(1), Const(2), Add, Return]);
+        assert!(!fold_constants(&mut before));
+    }
+
+    #[test]
+    fn store_load_pairs_are_collapsed() {
+        let mut before = code(&[Store(0), Load(0), Return]);
+        assert!(collapse_store_load(&mut before, 1));
+        assert_eq!(before, vec![None, None, Some(Return)]);
+
+        let mut before = code(&[Load(0), Store(0), Return]);
+        assert!(collapse_store_load(&mut before, 1));
+        assert_eq!(before, vec![None, None, Some(Return)]);
+    }
+
+    #[test]
+    fn stores_read_later_are_kept() {
+        let mut before = code(&[Store(0), Load(0), Load(0), Add, Return]);
+        assert!(!collapse_store_load(&mut before, 1));
+    }
+
+    #[test]
+    fn unreachable_code_is_removed() {
+        let mut before = code(&[Jump(2), Const(1), Return]);
+        assert!(remove_dead_code(&mut before));
+        assert_eq!(before, vec![Some(Jump(2)), None, Some(Return)]);
+    }
+
+    #[test]
+    fn unreachable_breaks_are_kept() {
+        // the inner loop is only left through `Return`
+        let func = Func {
+            name: String::from("f"),
+            args_count: 0,
+            locals_count: 0,
+            instrs: vec![Loop, Const(1), JumpIfFalse(7), Loop, Return, Break, Jump(0), Break, Return],
+        };
+
+        let optimized = optimize(&func);
+        assert_eq!(optimized.instrs, vec![Loop, Const(1), JumpIfFalse(6), Loop, Return, Break, Break, Return]);
+        assert_eq!(optimized.loop_end(0), Some(6));
+        assert_eq!(optimized.loop_end(3), Some(5));
+    }
+}
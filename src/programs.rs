@@ -0,0 +1,401 @@
+//! Example programs
+//!
+//! Bytecode of the dyon programs the VM is exercised with.
+
+use bytecode::Comp;
+use repr::Func;
+
+use super::Module;
+
+
+pub const NAMES: &[&str] = &["min_list", "sum", "two_loops", "break_loop", "nested_loops", "nested_call",
+                                     "print_loop", "recursion", "poly_loop", "const_flag",
+                                     "out_of_bounds", "overflow", "array_sink", "pending_call"];
+
+pub fn get(name: &str) -> Option<Module> {
+    match name {
+        "min_list" => Some(min_list()),
+        "sum" => Some(sum()),
+        "two_loops" => Some(two_loops()),
+        "break_loop" => Some(break_loop()),
+        "nested_loops" => Some(nested_loops()),
+        "nested_call" => Some(nested_call()),
+        "print_loop" => Some(print_loop()),
+        "recursion" => Some(recursion()),
+        "poly_loop" => Some(poly_loop()),
+        "const_flag" => Some(const_flag()),
+        "out_of_bounds" => Some(out_of_bounds()),
+        "overflow" => Some(overflow()),
+        "array_sink" => Some(array_sink()),
+        "pending_call" => Some(pending_call()),
+        _ => None,
+    }
+}
+
+
+/// [min_loop](https://github.com/greenMT/example-programs/blob/master/example-programs/dyon/min_loop.dyon)
+pub fn min_list() -> Module {
+    min_list_of(&[9, 3, 4, 5, 6, 1, 3, 2, 4])
+}
+
+/// `min_list` program, operating on a list with the given content.
+pub fn min_list_of(values: &[usize]) -> Module {
+    use bytecode::Instruction::*;
+
+    let mut main = vec![Array(values.len())];
+    for value in values {
+        main.push(Const(*value));
+        main.push(Push);
+    }
+    main.push(Call(String::from("min_list")));
+    main.push(Return);
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 0,
+                instrs: main,
+            }.into(),
+
+            "min".into() => Func {
+                name: "min".into(),
+                args_count: 2,
+                locals_count: 0,
+                instrs: vec![Load(1), Load(0), Cmp(Comp::Le), JumpIfFalse(6), Load(0), Jump(8), Load(1), Jump(8), Clone, Return]
+            }.into(),
+
+            "min_list".into() => Func {
+                name: "min_list".into(),
+                args_count: 1,
+                locals_count: 3,
+                instrs: vec![Load(0), Const(0), ArrayGet, Store(1), Load(0), Len, Store(2), Const(0), Store(3), Loop, Load(2), Load(3), Cmp(Comp::Lt), JumpIfFalse(25), Load(0), Load(3), ArrayGet, Load(1), Call(String::from("min")), Store(1), Load(3), Const(1), Add, Store(3), Jump(9), Break, Load(1), Print, Return],
+            }.into(),
+        }
+    }
+}
+
+/// Sums up `0..10`.
+///
+/// Written the way a naive compiler would emit it, so that the peephole
+/// optimizer has something to do.
+pub fn sum() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 3,
+                instrs: vec![Const(0), Store(0), Const(0), Store(1), Loop, Const(4), Const(6), Add, Load(1), Cmp(Comp::Lt), JumpIfFalse(22), Load(0), Load(1), Add, Store(2), Load(2), Store(0), Load(1), Const(1), Add, Store(1), Jump(24), Break, Jump(26), Jump(4), Print, Load(0), Print, Return],
+            }.into(),
+        }
+    }
+}
+
+/// Two functions, both with a loop at pc 4.
+///
+/// `sum_n` sums up `0..n`, `double_n` doubles `1` `n` times.
+pub fn two_loops() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 0,
+                instrs: vec![Const(10), Call(String::from("sum_n")), Print, Const(10), Call(String::from("double_n")), Print, Return],
+            }.into(),
+
+            "sum_n".into() => sum_n().into(),
+
+            "double_n".into() => Func {
+                name: "double_n".into(),
+                args_count: 1,
+                locals_count: 2,
+                instrs: vec![Const(1), Store(1), Const(0), Store(2), Loop, Load(0), Load(2), Cmp(Comp::Lt), JumpIfFalse(18), Load(1), Load(1), Add, Store(1), Load(2), Const(1), Add, Store(2), Jump(4), Break, Load(1), Return],
+            }.into(),
+        }
+    }
+}
+
+/// Loop without condition, left via `break`:
+///
+///     loop {
+///         if i > 5 { break }
+///         sum += i;
+///         i += 1;
+///     }
+pub fn break_loop() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 2,
+                instrs: vec![Const(0), Store(0), Const(0), Store(1), Loop, Load(0), Const(5), Cmp(Comp::Lt), JumpIfFalse(10), Jump(19), Load(1), Load(0), Add, Store(1), Load(0), Const(1), Add, Store(0), Jump(4), Break, Load(1), Print, Return],
+            }.into(),
+        }
+    }
+}
+
+/// Loop nested in another loop:
+///
+///     for i in 0..10 {
+///         for j in 0..10 {
+///             total += i + j;
+///         }
+///     }
+pub fn nested_loops() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 3,
+                instrs: vec![Const(0), Store(2), Const(0), Store(0), Loop, Const(10), Load(0), Cmp(Comp::Lt), JumpIfFalse(33), Const(0), Store(1), Loop, Const(10), Load(1), Cmp(Comp::Lt), JumpIfFalse(27), Load(2), Load(0), Add, Load(1), Add, Store(2), Load(1), Const(1), Add, Store(1), Jump(11), Break, Load(0), Const(1), Add, Store(0), Jump(4), Break, Load(2), Print, Return],
+            }.into(),
+        }
+    }
+}
+
+/// Loop calling a function with a loop:
+///
+///     for i in 0..10 {
+///         total += sum_n(i);
+///     }
+pub fn nested_call() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 2,
+                instrs: vec![Const(0), Store(1), Const(0), Store(0), Loop, Const(10), Load(0), Cmp(Comp::Lt), JumpIfFalse(19), Load(1), Load(0), Call(String::from("sum_n")), Add, Store(1), Load(0), Const(1), Add, Store(0), Jump(4), Break, Load(1), Print, Return],
+            }.into(),
+
+            "sum_n".into() => sum_n().into(),
+        }
+    }
+}
+
+/// Loop, which can't be traced (`Print` is not supported by traces):
+///
+///     for i in 0..10 {
+///         println(i);
+///     }
+pub fn print_loop() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 1,
+                instrs: vec![Const(0), Store(0), Loop, Const(10), Load(0), Cmp(Comp::Lt), JumpIfFalse(14), Load(0), Print, Load(0), Const(1), Add, Store(0), Jump(2), Break, Return],
+            }.into(),
+        }
+    }
+}
+
+/// Loop calling a recursive function:
+///
+///     fn up(i, n) {
+///         if i < n { up(i + 1, n) + 1 } else { 0 }
+///     }
+///
+///     for i in 0..10 {
+///         total += up(i, 4);
+///     }
+pub fn recursion() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 2,
+                instrs: vec![Const(0), Store(1), Const(0), Store(0), Loop, Const(10), Load(0), Cmp(Comp::Lt), JumpIfFalse(21), Const(4), Load(0), Call(String::from("up")), Load(1), Add, Store(1), Load(0), Const(1), Add, Store(0), Jump(4), Break, Load(1), Print, Return],
+            }.into(),
+
+            "up".into() => Func {
+                name: "up".into(),
+                args_count: 2,
+                locals_count: 0,
+                instrs: vec![Load(1), Load(0), Cmp(Comp::Lt), JumpIfFalse(12), Load(1), Load(0), Const(1), Add, Call(String::from("up")), Const(1), Add, Return, Const(0), Return],
+            }.into(),
+        }
+    }
+}
+
+/// Loop, which is entered with different types of `x`:
+///
+///     fn keep(x, n) {
+///         for i in 0..n {
+///             y = x;
+///         }
+///         y
+///     }
+///
+///     println(keep(7, 10));
+///     println(len(keep([3, 4], 10)));
+///     println(keep(8, 10));
+pub fn poly_loop() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 0,
+                instrs: vec![Const(7), Const(10), Call(String::from("keep")), Print, Array(2), Const(3), Push, Const(4), Push, Const(10), Call(String::from("keep")), Len, Print, Const(8), Const(10), Call(String::from("keep")), Print, Return],
+            }.into(),
+
+            "keep".into() => Func {
+                name: "keep".into(),
+                args_count: 2,
+                locals_count: 2,
+                instrs: vec![Const(0), Store(2), Loop, Load(0), Load(2), Cmp(Comp::Lt), JumpIfFalse(14), Load(1), Store(3), Load(2), Const(1), Add, Store(2), Jump(2), Break, Load(3), Return],
+            }.into(),
+        }
+    }
+}
+
+/// Sums up `0..n`.
+/// `step` is always called with the same flag, the branch on it folds away
+/// once the constant argument is propagated into the inlined frame.
+pub fn const_flag() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 2,
+                instrs: vec![Const(0), Store(1), Const(0), Store(0), Loop, Const(10), Load(0), Cmp(Comp::Lt), JumpIfFalse(18), Load(1), Const(1), Call(String::from("step")), Store(1), Load(0), Const(1), Add, Store(0), Jump(4), Break, Load(1), Print, Return],
+            }.into(),
+
+            "step".into() => Func {
+                name: "step".into(),
+                args_count: 2,
+                locals_count: 0,
+                instrs: vec![Load(0), Const(0), Cmp(Comp::Lt), JumpIfFalse(8), Load(1), Const(1), Add, Return, Load(1), Const(2), Add, Return],
+            }.into(),
+        }
+    }
+}
+
+/// The branch in `pick` changes halfway, its guard fails in the inlined
+/// frame while `total` is still on the stack of the loop frame:
+///
+///     fn pick(n) {
+///         if n < 5 { 1 } else { 2 }
+///     }
+///
+///     total := 0;
+///     for i in 0..10 {
+///         total = total + pick(i);
+///     }
+///     println(total);
+pub fn pending_call() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 2,
+                instrs: vec![Const(0), Store(0), Const(0), Store(1), Loop, Const(10), Load(1), Cmp(Comp::Lt), JumpIfFalse(19), Load(0), Load(1), Call(String::from("pick")), Add, Store(0), Load(1), Const(1), Add, Store(1), Jump(4), Break, Load(0), Print, Return],
+            }.into(),
+
+            "pick".into() => Func {
+                name: "pick".into(),
+                args_count: 1,
+                locals_count: 0,
+                instrs: vec![Const(5), Load(0), Cmp(Comp::Lt), JumpIfFalse(6), Const(1), Return, Const(2), Return],
+            }.into(),
+        }
+    }
+}
+
+/// Sums up an array of three elements, but loops five times. The fourth
+/// iteration raises an error.
+pub fn out_of_bounds() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 3,
+                instrs: vec![Array(3), Const(5), Push, Const(6), Push, Const(7), Push, Store(0), Const(0), Store(1), Const(0), Store(2), Loop, Const(5), Load(2), Cmp(Comp::Lt), JumpIfFalse(28), Load(0), Load(2), ArrayGet, Load(1), Add, Store(1), Load(2), Const(1), Add, Store(2), Jump(12), Break, Load(1), Print, Return],
+            }.into(),
+        }
+    }
+}
+
+/// Doubles `1` a hundred times, overflows in iteration 64.
+pub fn overflow() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 2,
+                instrs: vec![Const(1), Store(0), Const(0), Store(1), Loop, Const(100), Load(1), Cmp(Comp::Lt), JumpIfFalse(18), Load(0), Load(0), Add, Store(0), Load(1), Const(1), Add, Store(1), Jump(4), Break, Load(0), Print, Return],
+            }.into(),
+        }
+    }
+}
+
+/// `pair` builds a temporary array in every iteration, it never leaves the
+/// inlined frame.
+pub fn array_sink() -> Module {
+    use bytecode::Instruction::*;
+
+    Module {
+        funcs: btreemap!{
+            "main".into() => Func {
+                name: "main".into(),
+                args_count: 0,
+                locals_count: 2,
+                instrs: vec![Const(0), Store(1), Const(0), Store(0), Loop, Const(10), Load(0), Cmp(Comp::Lt), JumpIfFalse(19), Load(0), Call(String::from("pair")), Load(1), Add, Store(1), Load(0), Const(1), Add, Store(0), Jump(4), Break, Load(1), Print, Return],
+            }.into(),
+
+            "pair".into() => Func {
+                name: "pair".into(),
+                args_count: 1,
+                locals_count: 1,
+                instrs: vec![Array(2), Load(0), Push, Const(2), Push, Store(1), Load(1), Const(0), ArrayGet, Load(1), Len, Add, Return],
+            }.into(),
+        }
+    }
+}
+
+fn sum_n() -> Func {
+    use bytecode::Instruction::*;
+
+    Func {
+        name: "sum_n".into(),
+        args_count: 1,
+        locals_count: 2,
+        instrs: vec![Const(0), Store(1), Const(0), Store(2), Loop, Load(0), Load(2), Cmp(Comp::Lt), JumpIfFalse(18), Load(1), Load(2), Add, Store(1), Load(2), Const(1), Add, Store(2), Jump(4), Break, Load(1), Return],
+    }
+}
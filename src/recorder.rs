@@ -0,0 +1,195 @@
+//! Trace recording state
+//!
+//! Recording is a mode of the interpreter: while `Interpreter::recorder` is
+//! set, `Interpreter::step` executes instructions as usual and additionally
+//! emits the corresponding trace instructions into the `Recorder`. Thus the
+//! recorded trace does exactly what the interpreter did. Instructions the
+//! trace can't express stop recording before they are executed (see
+//! `Interpreter::recording_stops`).
+
+use std::mem;
+use std::rc::Rc;
+
+use kaktus::{PushPop, Stack};
+
+use abort::Abort;
+use ir;
+use recovery::{DeoptTable, FrameInfo, Guard};
+use repr::{Func, InstrPtr};
+
+use super::{Check, Trace, TraceInstruction};
+
+
+pub struct TraceDataAllocator {
+    pub total_size: usize,
+    offsets: Vec<usize>,
+}
+
+impl TraceDataAllocator {
+    pub fn new() -> Self {
+        TraceDataAllocator {
+            total_size: 0,
+            offsets: Vec::new(),
+        }
+    }
+
+    /// Allocator for the (inlined) frames in `call_tree`, new frames are
+    /// allocated after `total_size`.
+    pub fn for_frames(call_tree: &Stack<FrameInfo>, total_size: usize) -> Self {
+        let mut offsets = call_tree.walk().map(|frame| frame.offset).collect::<Vec<_>>();
+        offsets.reverse();
+
+        TraceDataAllocator {
+            total_size,
+            offsets,
+        }
+    }
+
+    // number of frames
+    pub fn depth(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn alloc(&mut self, to_allocate: usize) {
+        self.offsets.push(self.total_size);
+        // reserve space at the end
+        self.total_size += to_allocate;
+    }
+
+    pub fn pop(&mut self) {
+        self.offsets.pop().unwrap();
+    }
+
+    /// Makes sure the first `size` slots are allocated.
+    pub fn reserve(&mut self, size: usize) {
+        self.total_size = self.total_size.max(size);
+    }
+
+    pub fn current(&self) -> usize {
+        *self.offsets.last().unwrap()
+    }
+
+    pub fn at(&self, idx: usize) -> usize {
+        self.current() + idx
+    }
+}
+
+
+pub struct Recorder {
+    // `Loop` instruction the trace loops back to
+    pub header: InstrPtr,
+    loop_end: Option<usize>,
+    pub trace: Vec<TraceInstruction>,
+    // (inlined) frames of the instruction executed next
+    pub call_tree: Stack<FrameInfo>,
+    pub locals: TraceDataAllocator,
+    // frames of the guards and loop calls emitted so far
+    deopt: DeoptTable,
+    // number of values on the operand stack when recording started
+    entry_stack: usize,
+    // set once recording stopped
+    result: Option<Result<Trace, Abort>>,
+}
+
+impl Recorder {
+    pub fn new(header: &InstrPtr,
+               call_tree: Stack<FrameInfo>,
+               locals: TraceDataAllocator,
+               entry_stack: usize)
+               -> Self {
+        Recorder {
+            header: header.clone(),
+            loop_end: header.func.loop_end(header.pc),
+            trace: Vec::new(),
+            call_tree,
+            locals,
+            deopt: DeoptTable::default(),
+            entry_stack,
+            result: None,
+        }
+    }
+
+    pub fn emit(&mut self, instr: TraceInstruction) {
+        self.trace.push(instr);
+    }
+
+    /// Emits an instruction accessing local `idx` of the current frame.
+    pub fn emit_local(&mut self, instr: fn(usize) -> TraceInstruction, idx: usize) {
+        let slot = self.locals.at(idx);
+        self.trace.push(instr(slot));
+    }
+
+    /// Index of the current (inlined) frames in the deopt table of the trace.
+    pub fn frames(&mut self) -> usize {
+        self.deopt.add(&self.call_tree)
+    }
+
+    /// Emits a guard for the conditional jump `instr`, see `Guard::new`.
+    pub fn guard(&mut self, condition: bool, instr: &InstrPtr, exit_pc: usize, condition_on_stack: bool) {
+        let guard = Guard::new(condition,
+                               self.frames(),
+                               self.locals.depth() == 1,
+                               instr,
+                               exit_pc,
+                               self.loop_end,
+                               condition_on_stack);
+        self.trace.push(TraceInstruction::Guard(guard));
+    }
+
+    /// Emits a check of `instr`, if it fails the interpreter executes `instr`
+    /// again and raises the error.
+    pub fn check(&mut self, check: Check, instr: &InstrPtr) {
+        // overflows are not expected, the index is
+        let condition = matches!(check, Check::Index);
+        let guard = Guard::new(condition,
+                               self.frames(),
+                               self.locals.depth() == 1,
+                               instr,
+                               instr.pc,
+                               self.loop_end,
+                               false);
+        self.trace.push(TraceInstruction::Check(check, guard));
+    }
+
+    /// Inlines a call of `func`, its arguments are taken from the stack.
+    pub fn call(&mut self, func: &Rc<Func>, back_ref: &InstrPtr) {
+        self.locals.alloc(func.args_count + func.locals_count);
+
+        for idx in 0..func.args_count {
+            self.emit_local(TraceInstruction::Store, idx);
+        }
+
+        self.call_tree = self.call_tree.push(FrameInfo {
+            func: func.clone(),
+            back_ref: back_ref.clone(),
+            offset: self.locals.current(),
+        });
+    }
+
+    pub fn ret(&mut self) {
+        self.locals.pop();
+        self.call_tree = self.call_tree.pop().unwrap();
+    }
+
+    /// Whether `instr` is the loop header in the frame of the loop.
+    pub fn at_header(&self, instr: &InstrPtr) -> bool {
+        self.locals.depth() == 1 && instr.pc == self.header.pc
+    }
+
+    pub fn stop(&mut self, result: Result<(), Abort>) {
+        self.result = Some(result.map(|()| {
+            info!(target: "trace", "{:?}", self.trace);
+            let ir = ir::build(&self.trace, self.entry_stack);
+            info!(target: "trace", "IR:\n{}", ir::dump(&ir));
+            Trace::new(ir, self.locals.total_size, mem::take(&mut self.deopt))
+        }));
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.result.is_some()
+    }
+
+    pub fn into_result(self) -> Result<Trace, Abort> {
+        self.result.expect("recording did not stop")
+    }
+}
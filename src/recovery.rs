@@ -1,8 +1,10 @@
 
 use std::rc::Rc;
+use std::convert::TryFrom;
 use std::fmt;
+use std::mem;
 
-use kaktus::Stack;
+use kaktus::{PushPop, Stack};
 
 use super::Func;
 use repr::InstrPtr;
@@ -20,14 +22,314 @@ pub struct FrameInfo {
 pub struct Guard {
     // condition guard protects
     pub condition: bool,
-    // frame information to recover from
-    pub frame: Stack<FrameInfo>,
+    // inlined frames to recover, index into the `DeoptTable` of the trace
+    pub frames: usize,
     // pc position where execution can continue
     pub pc: usize,
+    // whether the instruction at `pc` expects the condition on the stack,
+    // it is the top of the snapshot then. Superinstructions evaluate it
+    // again instead.
+    pub condition_on_stack: bool,
+    // failing the guard leaves the traced loop, `pc` points behind its `Break`
+    pub loop_exit: bool,
+    // where the interpreter continues after evaluating the guarded
+    // instruction, if the condition does not hold
+    pub exit_pc: usize,
+}
+
+impl Guard {
+    /// Guard for the conditional jump `instr`.
+    ///
+    /// `exit_pc` is where the interpreter continues if the condition does not
+    /// hold. If that leads out of the traced loop (whose `Break` is at
+    /// `loop_end`), the guard becomes a loop exit: the interpreter resumes
+    /// right after the loop instead of evaluating the jump again. Only jumps
+    /// in the frame of the loop itself (`in_loop_frame`) can leave it.
+    pub fn new(condition: bool,
+               frames: usize,
+               in_loop_frame: bool,
+               instr: &InstrPtr,
+               exit_pc: usize,
+               loop_end: Option<usize>,
+               condition_on_stack: bool)
+               -> Self {
+        match loop_end {
+            Some(end) if in_loop_frame && instr.func.follow_jumps(exit_pc) == end => {
+                Guard {
+                    condition,
+                    frames,
+                    pc: end + 1,
+                    condition_on_stack: false,
+                    loop_exit: true,
+                    exit_pc: end + 1,
+                }
+            }
+
+            _ => {
+                Guard {
+                    condition,
+                    frames,
+                    pc: instr.pc,
+                    condition_on_stack,
+                    loop_exit: false,
+                    exit_pc,
+                }
+            }
+        }
+    }
 }
 
 impl fmt::Debug for Guard {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "-{:?}-", self.condition)
+        if self.loop_exit {
+            write!(f, "-{:?}- exit", self.condition)
+        } else {
+            write!(f, "-{:?}-", self.condition)
+        }
+    }
+}
+
+/// Frames to recover for the guards (and inner loop calls) of a trace,
+/// filled while recording.
+///
+/// Guards with the same inline stack share its frames and only keep its
+/// index. Functions are kept once per trace, frames refer to them by index.
+/// Every frame returns into the previous one, its locals start `delta` slots
+/// after those of the previous one.
+#[derive(Default)]
+pub struct DeoptTable {
+    funcs: Vec<Rc<Func>>,
+    frames: Vec<DeoptFrame>,
+    // range of `frames` of every inline stack
+    stacks: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeoptFrame {
+    func: u32,
+    // where the previous frame continues, unused for the first frame
+    pub return_pc: u32,
+    pub delta: u32,
+}
+
+impl DeoptTable {
+    /// Index of the inline stack `call_tree`, which is added unless the
+    /// table has it already.
+    pub fn add(&mut self, call_tree: &Stack<FrameInfo>) -> usize {
+        let frames = self.encode(call_tree);
+        let existing = self.stacks
+            .iter()
+            .position(|&(start, end)| self.frames[start as usize..end as usize] == frames[..]);
+
+        existing.unwrap_or_else(|| {
+            let start = index(self.frames.len());
+            self.frames.extend_from_slice(&frames);
+            self.stacks.push((start, index(self.frames.len())));
+            self.stacks.len() - 1
+        })
+    }
+
+    fn encode(&mut self, call_tree: &Stack<FrameInfo>) -> Vec<DeoptFrame> {
+        let mut infos = call_tree.walk().collect::<Vec<_>>();
+        infos.reverse();
+
+        let mut frames = Vec::with_capacity(infos.len());
+        let mut offset = 0;
+        for (idx, info) in infos.iter().enumerate() {
+            debug_assert!(idx == 0 || Rc::ptr_eq(&info.back_ref.func, &infos[idx - 1].func));
+
+            let func = match self.funcs.iter().position(|func| Rc::ptr_eq(func, &info.func)) {
+                Some(func) => func,
+                None => {
+                    self.funcs.push(info.func.clone());
+                    self.funcs.len() - 1
+                }
+            };
+            frames.push(DeoptFrame {
+                func: index(func),
+                return_pc: index(info.back_ref.pc),
+                delta: index(info.offset - offset),
+            });
+            offset = info.offset;
+        }
+        frames
+    }
+
+    /// Frames of inline stack `idx`, least recent first.
+    pub fn frames(&self, idx: usize) -> &[DeoptFrame] {
+        let (start, end) = self.stacks[idx];
+        &self.frames[start as usize..end as usize]
+    }
+
+    pub fn func(&self, frame: &DeoptFrame) -> &Rc<Func> {
+        &self.funcs[frame.func as usize]
+    }
+
+    /// Function of the innermost frame of inline stack `idx`, where the
+    /// interpreter continues.
+    pub fn innermost(&self, idx: usize) -> &Rc<Func> {
+        self.func(self.frames(idx).last().unwrap())
+    }
+
+    /// Slots of the frames of inline stack `idx`.
+    pub fn slots(&self, idx: usize) -> Vec<usize> {
+        let mut offset = 0;
+        self.frames(idx)
+            .iter()
+            .flat_map(|frame| {
+                offset += frame.delta as usize;
+                let func = self.func(frame);
+                offset..offset + func.args_count + func.locals_count
+            })
+            .collect()
+    }
+
+    /// The frames of inline stack `idx` again, the first one returns to
+    /// `back_ref`.
+    pub fn call_tree(&self, idx: usize, back_ref: InstrPtr) -> Stack<FrameInfo> {
+        let frames = self.frames(idx);
+        let mut call_tree = Stack::root(FrameInfo {
+            func: self.func(&frames[0]).clone(),
+            back_ref,
+            offset: frames[0].delta as usize,
+        });
+
+        let mut offset = frames[0].delta as usize;
+        for (prev, frame) in frames.iter().zip(&frames[1..]) {
+            offset += frame.delta as usize;
+            call_tree = call_tree.push(FrameInfo {
+                func: self.func(frame).clone(),
+                back_ref: InstrPtr::new(self.func(prev).clone(), frame.return_pc as usize),
+                offset,
+            });
+        }
+        call_tree
+    }
+
+    /// Number of distinct inline stacks.
+    pub fn stack_count(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// Approximate number of bytes used by the table.
+    pub fn size(&self) -> usize {
+        self.funcs.len() * mem::size_of::<Rc<Func>>() +
+        self.frames.len() * mem::size_of::<DeoptFrame>() +
+        self.stacks.len() * mem::size_of::<(u32, u32)>()
+    }
+}
+
+/// `n` as stored in the table. Traces are far too short to get near the
+/// limit, but a wrapped index would recover the wrong frames.
+fn index(n: usize) -> u32 {
+    u32::try_from(n).expect("deopt table index does not fit into u32")
+}
+
+impl fmt::Debug for DeoptTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeoptTable({} inline stacks)", self.stacks.len())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::rc::Rc;
+
+    use kaktus::{PushPop, Stack};
+
+    use ir::Ir;
+    use repr::{Func, InstrPtr};
+    use testing;
+
+    use super::{DeoptTable, FrameInfo};
+
+    fn func(name: &str, args_count: usize, locals_count: usize) -> Rc<Func> {
+        Rc::new(Func {
+            name: name.into(),
+            args_count,
+            locals_count,
+            instrs: vec![],
+        })
+    }
+
+    /// The frame of `main` and the one of `min` inlined into it, calling
+    /// from `pc`.
+    fn inline_stacks(main: &Rc<Func>, min: &Rc<Func>, pc: usize) -> (Stack<FrameInfo>, Stack<FrameInfo>) {
+        let root = Stack::root(FrameInfo {
+            func: main.clone(),
+            back_ref: InstrPtr::new(main.clone(), 0),
+            offset: 0,
+        });
+        let inlined = root.push(FrameInfo {
+            func: min.clone(),
+            back_ref: InstrPtr::new(main.clone(), pc),
+            offset: 2,
+        });
+        (root, inlined)
+    }
+
+    #[test]
+    fn equal_inline_stacks_are_shared() {
+        let (main, min) = (func("main", 0, 2), func("min", 2, 0));
+        let (root, inlined) = inline_stacks(&main, &min, 7);
+        let (root_again, inlined_again) = inline_stacks(&main, &min, 7);
+
+        let mut table = DeoptTable::default();
+        assert_eq!(table.add(&root), 0);
+        assert_eq!(table.add(&inlined), 1);
+        assert_eq!(table.add(&root_again), 0);
+        assert_eq!(table.add(&inlined_again), 1);
+
+        assert_eq!(table.stack_count(), 2);
+        assert_eq!(table.frames.len(), 3);
+        assert_eq!(table.funcs.len(), 2);
+    }
+
+    #[test]
+    fn inline_stacks_returning_elsewhere_are_not_shared() {
+        let (main, min) = (func("main", 0, 2), func("min", 2, 0));
+        let mut table = DeoptTable::default();
+        assert_eq!(table.add(&inline_stacks(&main, &min, 7).1), 0);
+        assert_eq!(table.add(&inline_stacks(&main, &min, 9).1), 1);
+
+        // functions are still kept once
+        assert_eq!(table.funcs.len(), 2);
+    }
+
+    #[test]
+    fn inline_stacks_are_recovered() {
+        let (main, min) = (func("main", 0, 2), func("min", 2, 0));
+        let mut table = DeoptTable::default();
+        let idx = table.add(&inline_stacks(&main, &min, 7).1);
+
+        assert_eq!(table.innermost(idx).name, "min");
+        assert_eq!(table.slots(idx), [0, 1, 2, 3]);
+
+        let back_ref = InstrPtr::new(main.clone(), 3);
+        let frames = table.call_tree(idx, back_ref).walk()
+            .map(|info| (info.func.name.clone(), info.back_ref.func.name.clone(), info.back_ref.pc, info.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(frames, [(String::from("min"), String::from("main"), 7, 2),
+                            (String::from("main"), String::from("main"), 3, 0)]);
+    }
+
+    #[test]
+    fn min_list_guards_share_inline_stacks() {
+        let (_, _, trace) = testing::trace_of("min_list", &[]);
+        let guards = trace.trace
+            .iter()
+            .filter_map(|op| match *op {
+                Ir::Guard(_, ref guard, _) | Ir::Exit(ref guard, _) => Some(guard.frames),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // the frame of `min_list`, and the one of `min` inlined into it
+        let stacks = guards.iter().cloned().collect::<BTreeSet<_>>();
+        assert_eq!(trace.deopt.stack_count(), 2);
+        assert_eq!(stacks.len(), 2);
+        assert!(guards.len() > stacks.len());
     }
 }
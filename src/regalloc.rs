@@ -0,0 +1,146 @@
+//! Register allocation for traces
+//!
+//! The runner keeps the values of a trace in registers, instead of one
+//! value per instruction. Registers are assigned by a linear scan over the
+//! trace: a value occupies its register from its definition to its last use,
+//! afterwards the register is reused.
+//!
+//! Uses include snapshots, guards read those values when they fail. Values
+//! of sunk arrays (`Ir::Sunk`) are never computed, using them means using
+//! their elements. Values of the preamble of a peeled trace, which the loop
+//! uses, stay live until the end of the trace.
+//!
+//! Register 0 is written by instructions, which don't define a value, and
+//! never read.
+
+use ir::{self, Ir};
+
+use super::Trace;
+
+
+/// Assigns registers to the values of `trace`, see `Trace::registers`.
+pub fn allocate(trace: &mut Trace) {
+    let ir = &trace.trace;
+    let last_use = last_uses(ir, trace.loop_start);
+
+    let mut registers = vec![0; ir.len()];
+    let mut free: Vec<usize> = Vec::new();
+    let mut count = 1;
+    // registers to free after the instruction, by instruction
+    let mut expiring: Vec<Vec<usize>> = vec![Vec::new(); ir.len()];
+
+    for (idx, op) in ir.iter().enumerate() {
+        // operands are read before the result is written, registers of
+        // values used for the last time can be reused right away
+        free.append(&mut expiring[idx]);
+
+        if !ir::defines_value(op) || matches!(*op, Ir::Sunk(_)) {
+            continue;
+        }
+        let last = match last_use[idx] {
+            Some(last) => last,
+            // written, but never read
+            None => continue,
+        };
+
+        let register = free.pop().unwrap_or_else(|| {
+            count += 1;
+            count - 1
+        });
+        registers[idx] = register;
+        if last < ir.len() {
+            expiring[last].push(register);
+        }
+    }
+
+    info!("O: {} registers for {} instructions", count, ir.len());
+    trace.registers = registers;
+    trace.register_count = count;
+}
+
+/// Index of the last instruction reading the value of every instruction,
+/// `ir.len()` for values which are live until the end.
+fn last_uses(ir: &[Ir], loop_start: usize) -> Vec<Option<usize>> {
+    let mut last_use = vec![None; ir.len()];
+
+    for (idx, op) in ir.iter().enumerate() {
+        for val in op.operands() {
+            // used by the loop, the preamble doesn't run again
+            let end = if val < loop_start && idx >= loop_start { ir.len() } else { idx };
+
+            let vals = match ir[val] {
+                Ir::Sunk(ref elements) => elements.clone(),
+                _ => vec![val],
+            };
+            for val in vals {
+                last_use[val] = last_use[val].max(Some(end));
+            }
+        }
+    }
+
+    last_use
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ir::{self, Ir};
+    use recovery::DeoptTable;
+    use testing::min_list_trace;
+    use Trace;
+
+    use super::{allocate, last_uses};
+
+    /// Registers of `ir` with the loop starting at `loop_start`, and their
+    /// number.
+    fn registers(ir: Vec<Ir>, loop_start: usize) -> (Vec<usize>, usize) {
+        let mut trace = Trace::new(ir, 1, DeoptTable::default());
+        trace.loop_start = loop_start;
+        allocate(&mut trace);
+        (trace.registers, trace.register_count)
+    }
+
+    #[test]
+    fn registers_are_reused_after_the_last_use() {
+        let ir = vec![Ir::Const(1), Ir::Const(2), Ir::Add(0, 1), Ir::Const(3), Ir::Add(2, 3), Ir::Store(0, 4)];
+        assert_eq!(registers(ir, 0), (vec![1, 2, 2, 1, 1, 0], 3));
+    }
+
+    #[test]
+    fn unused_values_get_no_register() {
+        let ir = vec![Ir::Const(1), Ir::Const(2), Ir::Store(0, 1)];
+        assert_eq!(registers(ir, 0), (vec![0, 1, 0], 2));
+    }
+
+    #[test]
+    fn preamble_values_used_by_the_loop_are_never_reused() {
+        let ir = vec![Ir::Const(1), Ir::Load(0), Ir::Add(0, 1), Ir::Store(0, 2),
+                      Ir::Load(0), Ir::Add(0, 4), Ir::Store(0, 5)];
+        let (registers, count) = registers(ir, 4);
+
+        assert_eq!(registers[0], 1);
+        assert!(registers[1..].iter().all(|&register| register != 1));
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn min_list_live_values_have_distinct_registers() {
+        let trace = min_list_trace("forward,dse,sink,bce,fold,peel,dce");
+        let ir = &trace.trace;
+        let last_use = last_uses(ir, trace.loop_start);
+        assert!(trace.register_count < ir.len());
+
+        for (idx, op) in ir.iter().enumerate() {
+            let register = trace.registers[idx];
+            assert!(register < trace.register_count);
+            assert_eq!(register == 0, !ir::defines_value(op) || last_use[idx].is_none(), "{:04} {:?}", idx, op);
+
+            // values defined while `idx` is live, the last use may write
+            // the register of its operand
+            let live = idx + 1..last_use[idx].unwrap_or(idx + 1).min(ir.len());
+            for other in live.filter(|&other| trace.registers[other] != 0) {
+                assert_ne!(trace.registers[other], register, "{:04} and {:04}", idx, other);
+            }
+        }
+    }
+}
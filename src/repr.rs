@@ -2,7 +2,7 @@
 use std::rc::Rc;
 use std::ops::Deref;
 
-use bytecode::Instruction;
+use bytecode::{self, Instruction};
 
 #[derive(Debug, Clone)]
 pub struct Func {
@@ -12,7 +12,29 @@ pub struct Func {
     pub instrs: Vec<Instruction>,
 }
 
-#[derive(Debug, Clone)]
+impl Func {
+    /// pc of the `Break` which closes the loop starting at `header`.
+    pub fn loop_end(&self, header: usize) -> Option<usize> {
+        let mut depth = 0;
+        for (pc, instr) in self.instrs.iter().enumerate().skip(header + 1) {
+            match *instr {
+                Instruction::Loop => depth += 1,
+                Instruction::Break if depth == 0 => return Some(pc),
+                Instruction::Break => depth -= 1,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// Follows unconditional jumps, starting at `pc`.
+    pub fn follow_jumps(&self, pc: usize) -> usize {
+        bytecode::follow_jumps(pc, self.instrs.len(), |pc| self.instrs.get(pc))
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Null,
     Bool(bool),
@@ -20,7 +42,28 @@ pub enum Value {
     Array(Vec<usize>),
 }
 
+impl Value {
+    pub fn ty(&self) -> Type {
+        match *self {
+            Value::Null => Type::Null,
+            Value::Bool(_) => Type::Bool,
+            Value::Usize(_) => Type::Usize,
+            Value::Array(_) => Type::Array,
+        }
+    }
+}
+
+/// Type of a `Value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Null,
+    Bool,
+    Usize,
+    Array,
+}
+
 
+#[derive(Clone)]
 pub struct CallFrame {
     pub back_ref: InstrPtr,
     pub args_count: usize,
@@ -30,7 +73,7 @@ pub struct CallFrame {
 impl CallFrame {
     pub fn for_fn(func: &Func, back_ref: InstrPtr) -> Self {
         CallFrame {
-            back_ref: back_ref,
+            back_ref,
             args_count: func.args_count,
             locals: vec![Value::Null; func.args_count + func.locals_count],
         }
@@ -47,8 +90,8 @@ pub struct InstrPtr {
 impl InstrPtr {
     pub fn new(func: Rc<Func>, pc: usize) -> Self {
         InstrPtr {
-            func: func,
-            pc: pc,
+            func,
+            pc,
         }
     }
 
@@ -0,0 +1,144 @@
+//! Superinstructions
+//!
+//! Fuses common instruction sequences into a single instruction, which saves
+//! dispatch overhead in the interpreter:
+//!
+//!     Load(a), Load(b), Cmp(how), JumpIfFalse(t) => CmpLocalsJumpIfFalse(a, b, how, t)
+//!     Load(x), Const(n), Add, Store(x)           => IncLocal(x, n)
+//!
+//! Like the peephole optimizer, sequences are only fused if none of the
+//! instructions but the first is a jump target.
+//!
+//! The trace recorder expands superinstructions again, thus traces are the
+//! same with and without this pass.
+
+use std::rc::Rc;
+
+use bytecode::Instruction::*;
+use peephole::{self, Code};
+use repr::Func;
+
+use super::Module;
+
+
+pub fn fuse_module(module: &Module) -> Module {
+    Module {
+        funcs: module.funcs
+            .iter()
+            .map(|(name, func)| (name.clone(), Rc::new(fuse(func))))
+            .collect(),
+    }
+}
+
+pub fn fuse(func: &Func) -> Func {
+    let mut code: Code = func.instrs.iter().cloned().map(Some).collect();
+    let targets = peephole::jump_targets(&code);
+    let pcs = peephole::live_pcs(&code);
+
+    let mut idx = 0;
+    while idx + 4 <= pcs.len() {
+        let window = &pcs[idx..idx + 4];
+
+        let fused = if window[1..].iter().any(|pc| targets.contains(pc)) {
+            None
+        } else {
+            match (&code[window[0]], &code[window[1]], &code[window[2]], &code[window[3]]) {
+                (&Some(Load(a)), &Some(Load(b)), &Some(Cmp(how)), &Some(JumpIfFalse(target))) => {
+                    Some(CmpLocalsJumpIfFalse(a, b, how, target))
+                }
+
+                (&Some(Load(x)), &Some(Const(n)), &Some(Add), &Some(Store(y))) if x == y => {
+                    Some(IncLocal(x, n))
+                }
+
+                _ => None,
+            }
+        };
+
+        match fused {
+            Some(instr) => {
+                // keep fused instruction at the first pc, jumps to the
+                // sequence stay valid
+                code[window[0]] = Some(instr);
+                for pc in &window[1..] {
+                    code[*pc] = None;
+                }
+                idx += 4;
+            }
+            None => idx += 1,
+        }
+    }
+
+    let instrs = peephole::compact(&code);
+    info!(target: "superinstructions", "{}: {:?}", func.name, instrs);
+
+    Func {
+        name: func.name.clone(),
+        args_count: func.args_count,
+        locals_count: func.locals_count,
+        instrs,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytecode::{Comp, Instruction};
+    use bytecode::Instruction::*;
+    use repr::Func;
+
+    use super::fuse;
+
+    fn fused(instrs: &[Instruction]) -> Vec<Instruction> {
+        let func = Func {
+            name: String::from("f"),
+            args_count: 0,
+            locals_count: 2,
+            instrs: instrs.to_vec(),
+        };
+        fuse(&func).instrs
+    }
+
+    #[test]
+    fn compare_and_jump_of_locals_is_fused() {
+        let instrs = fused(&[Load(0), Load(1), Cmp(Comp::Lt), JumpIfFalse(5), Const(1), Return]);
+        assert_eq!(instrs, vec![CmpLocalsJumpIfFalse(0, 1, Comp::Lt, 2), Const(1), Return]);
+    }
+
+    #[test]
+    fn increment_of_a_local_is_fused() {
+        let instrs = fused(&[Load(1), Const(2), Add, Store(1), Return]);
+        assert_eq!(instrs, vec![IncLocal(1, 2), Return]);
+    }
+
+    #[test]
+    fn addition_stored_to_another_local_is_kept() {
+        let instrs = fused(&[Load(0), Const(2), Add, Store(1), Return]);
+        assert_eq!(instrs, vec![Load(0), Const(2), Add, Store(1), Return]);
+    }
+
+    #[test]
+    fn sequences_containing_jump_targets_are_kept() {
+        let instrs = [Load(0), Load(1), Cmp(Comp::Lt), JumpIfFalse(5), Jump(1), Return];
+        assert_eq!(fused(&instrs), instrs.to_vec());
+    }
+
+    #[test]
+    fn jumps_are_rewritten_to_the_fused_code() {
+        // a loop counting local 0 up to local 1
+        let instrs = fused(&[Loop,
+                             Load(0),
+                             Load(1),
+                             Cmp(Comp::Lt),
+                             JumpIfFalse(10),
+                             Load(0),
+                             Const(1),
+                             Add,
+                             Store(0),
+                             Jump(1),
+                             Break,
+                             Return]);
+        assert_eq!(instrs,
+                   vec![Loop, CmpLocalsJumpIfFalse(0, 1, Comp::Lt, 4), IncLocal(0, 1), Jump(1), Break, Return]);
+    }
+}
@@ -0,0 +1,95 @@
+//! Helpers for tests running the example programs
+
+use std::rc::Rc;
+
+use config::Config;
+use programs;
+use repr::{CallFrame, InstrPtr, Value};
+
+use super::{prepare, Interpreter, Module, Trace};
+
+
+/// Configuration with `flags`, loops get hot after two iterations and
+/// `Print` writes no output.
+pub fn config(flags: &[&str]) -> Config {
+    let flags = Some("--hot-threshold=2").into_iter().chain(flags.iter().cloned());
+    let mut config = Config::parse(flags.map(String::from)).unwrap();
+    config.quiet = true;
+    config
+}
+
+/// Example program `name`, rewritten as configured.
+pub fn program(name: &str, config: &Config) -> Module {
+    prepare(programs::get(name).unwrap(), config)
+}
+
+/// Runs `module` until it ends.
+pub fn run<'a>(module: &'a Module, config: Config) -> Interpreter<'a> {
+    let mut interpreter = Interpreter::with_config(module, config);
+    interpreter.run_program().unwrap();
+    interpreter
+}
+
+/// Output of example program `name`, run with `flags`.
+pub fn output(name: &str, flags: &[&str]) -> Vec<usize> {
+    let config = config(flags);
+    let module = program(name, &config);
+    let interpreter = run(&module, config);
+    interpreter.output().to_vec()
+}
+
+/// Traces of `interpreter` with the function and pc of their loop.
+pub fn traces(interpreter: &Interpreter) -> Vec<(String, usize, Rc<Trace>)> {
+    interpreter.traces
+        .iter()
+        .map(|info| (String::from(info.func), info.pc, info.trace.clone()))
+        .collect()
+}
+
+/// The only trace of example program `name`, with the module it was
+/// recorded for and the `Loop` instruction it belongs to.
+pub fn trace_of(name: &str, flags: &[&str]) -> (Module, InstrPtr, Rc<Trace>) {
+    let config = config(flags);
+    let module = program(name, &config);
+    let (func, pc, trace) = {
+        let interpreter = run(&module, config);
+        let mut traces = traces(&interpreter);
+        assert_eq!(traces.len(), 1, "traces of {}", name);
+        traces.pop().unwrap()
+    };
+
+    let header = InstrPtr::new(module.funcs[&func].clone(), pc);
+    (module, header, trace)
+}
+
+/// The trace of the loop of `min_list`, optimized by `passes`.
+pub fn min_list_trace(passes: &str) -> Rc<Trace> {
+    trace_of("min_list", &[&format!("--passes={}", passes)]).2
+}
+
+/// Enters `trace` of the loop at `header` in a new frame with `locals`.
+///
+/// Returns where the interpreter continues and whether the loop was left,
+/// the state of the interpreter is the one after leaving the trace.
+pub fn run_trace(interpreter: &mut Interpreter, header: &InstrPtr, trace: &Trace, locals: &[Value]) -> (InstrPtr, bool) {
+    let mut frame = CallFrame::for_fn(&header.func, InstrPtr::new(header.func.clone(), 0));
+    frame.locals[..locals.len()].clone_from_slice(locals);
+    interpreter.frames.push(frame);
+
+    let (next, _, left_loop) = interpreter.run_trace(header, trace);
+    (next, left_loop)
+}
+
+/// State of the interpreter after entering the trace of example program
+/// `name`, run with `flags`, with `locals`: the function and pc it
+/// continues at, whether the loop was left, the locals of its frames and
+/// its operand stack.
+pub fn trace_exit(name: &str, flags: &[&str], locals: &[Value]) -> (String, usize, bool, Vec<Vec<Value>>, Vec<Value>) {
+    let (module, header, trace) = trace_of(name, flags);
+    let mut interpreter = Interpreter::with_config(&module, config(flags));
+    let (next, left_loop) = run_trace(&mut interpreter, &header, &trace, locals);
+
+    let frames = interpreter.frames.iter().map(|frame| frame.locals.clone()).collect();
+    (next.func.name.clone(), next.pc, left_loop, frames, interpreter.stack.clone())
+}
+
@@ -0,0 +1,275 @@
+//! Cache of recorded traces
+//!
+//! Traces are keyed by the function containing the loop and the pc of its
+//! `Loop` instruction. Functions are identified by name, which is unique
+//! within a `Module`.
+//!
+//! A loop might have several traces, specialized for different types of the
+//! locals when entering the loop (see `Trace::entry_types`). Looking up a
+//! trace returns the one accepting the current locals.
+//!
+//! The memory used by all cached traces is capped. If a new trace does not
+//! fit anymore, the least recently executed traces are evicted. An evicted
+//! loop is traced again once it becomes hot.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use repr::Value;
+
+use super::Trace;
+
+
+struct Entry {
+    trace: Rc<Trace>,
+    size: usize,
+    // value of `TraceCache::clock` when the trace was last used
+    last_used: u64,
+    executions: u64,
+}
+
+/// Information about a cached trace, see `TraceCache::iter`.
+pub struct TraceInfo<'a> {
+    pub func: &'a str,
+    pub pc: usize,
+    pub trace: &'a Rc<Trace>,
+    pub size: usize,
+    pub executions: u64,
+}
+
+
+pub struct TraceCache {
+    // function name -> pc of `Loop` instruction -> specialized traces
+    traces: BTreeMap<String, BTreeMap<usize, Vec<Entry>>>,
+    // maximum number of bytes used by all traces
+    capacity: usize,
+    used: usize,
+    clock: u64,
+}
+
+impl TraceCache {
+    pub fn new(capacity: usize) -> Self {
+        TraceCache {
+            traces: BTreeMap::new(),
+            capacity,
+            used: 0,
+            clock: 0,
+        }
+    }
+
+    /// Looks up the trace for a loop, which accepts the `locals` of the frame
+    /// containing the loop, and marks it as used.
+    pub fn get(&mut self, func: &str, pc: usize, locals: &[Value]) -> Option<Rc<Trace>> {
+        self.clock += 1;
+        let clock = self.clock;
+
+        self.traces
+            .get_mut(func)
+            .and_then(|loops| loops.get_mut(&pc))
+            .and_then(|entries| entries.iter_mut().find(|entry| entry.trace.accepts(locals)))
+            .map(|entry| {
+                entry.last_used = clock;
+                entry.executions += 1;
+                entry.trace.clone()
+            })
+    }
+
+    pub fn contains(&self, func: &str, pc: usize) -> bool {
+        self.traces.get(func).is_some_and(|loops| loops.contains_key(&pc))
+    }
+
+    /// Adds a trace, evicting least recently used traces to make room. A
+    /// trace with the same entry types is replaced.
+    ///
+    /// Returns `false` if the trace is larger than the whole cache, in which
+    /// case it is not added.
+    pub fn insert(&mut self, func: &str, pc: usize, trace: Rc<Trace>) -> bool {
+        let size = trace.memory_size();
+        if size > self.capacity {
+            info!("C: trace @{:}[{:}] ({:} bytes) exceeds cache", func, pc, size);
+            return false;
+        }
+
+        let replaced = self.find(func, pc, |entry| entry.trace.entry_types == trace.entry_types);
+        if let Some(idx) = replaced {
+            self.remove(func, pc, idx);
+        }
+        while self.used + size > self.capacity {
+            self.evict_lru();
+        }
+
+        self.clock += 1;
+        self.used += size;
+        self.traces.entry(func.into()).or_default().entry(pc).or_default().push(Entry {
+            trace,
+            size,
+            last_used: self.clock,
+            executions: 0,
+        });
+        true
+    }
+
+    /// Updates the size of a trace, after bridges were attached to it.
+    pub fn refresh(&mut self, func: &str, pc: usize, trace: &Rc<Trace>) {
+        let resized = self.traces
+            .get_mut(func)
+            .and_then(|loops| loops.get_mut(&pc))
+            .and_then(|entries| entries.iter_mut().find(|entry| Rc::ptr_eq(&entry.trace, trace)))
+            .map(|entry| {
+                let old = entry.size;
+                entry.size = entry.trace.memory_size();
+                (old, entry.size)
+            });
+
+        if let Some((old, new)) = resized {
+            self.used = self.used - old + new;
+            while self.used > self.capacity {
+                self.evict_lru();
+            }
+        }
+    }
+
+    /// Evicts all traces of a loop.
+    pub fn evict(&mut self, func: &str, pc: usize) -> Vec<Rc<Trace>> {
+        let entries = self.traces.get_mut(func).and_then(|loops| loops.remove(&pc));
+        entries.unwrap_or_default()
+            .into_iter()
+            .map(|entry| {
+                self.used -= entry.size;
+                entry.trace
+            })
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.traces.clear();
+        self.used = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = TraceInfo<'_>> {
+        self.traces.iter().flat_map(|(func, loops)| {
+            loops.iter().flat_map(move |(pc, entries)| {
+                entries.iter().map(move |entry| {
+                    TraceInfo {
+                        func: func.as_str(),
+                        pc: *pc,
+                        trace: &entry.trace,
+                        size: entry.size,
+                        executions: entry.executions,
+                    }
+                })
+            })
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.traces.values().flat_map(BTreeMap::values).map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of bytes used by all cached traces.
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    fn evict_lru(&mut self) {
+        let lru = self.traces
+            .iter()
+            .flat_map(|(func, loops)| {
+                loops.iter().flat_map(move |(pc, entries)| {
+                    entries.iter().enumerate().map(move |(idx, entry)| (entry.last_used, func, *pc, idx))
+                })
+            })
+            .min()
+            .map(|(_, func, pc, idx)| (func.clone(), pc, idx));
+
+        if let Some((func, pc, idx)) = lru {
+            info!("C: evicting trace @{:}[{:}]", func, pc);
+            self.remove(&func, pc, idx);
+        }
+    }
+
+    /// Index of the first trace of a loop matching `pred`.
+    fn find<P: Fn(&Entry) -> bool>(&self, func: &str, pc: usize, pred: P) -> Option<usize> {
+        self.traces
+            .get(func)
+            .and_then(|loops| loops.get(&pc))
+            .and_then(|entries| entries.iter().position(pred))
+    }
+
+    fn remove(&mut self, func: &str, pc: usize, idx: usize) {
+        let loops = self.traces.get_mut(func).unwrap();
+        let entry = loops.get_mut(&pc).unwrap().remove(idx);
+        self.used -= entry.size;
+
+        if loops[&pc].is_empty() {
+            loops.remove(&pc);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use ir::Ir;
+    use recovery::DeoptTable;
+
+    use super::super::Trace;
+    use super::TraceCache;
+
+    fn trace(len: usize) -> Rc<Trace> {
+        Rc::new(Trace::new(vec![Ir::Nop; len], 0, DeoptTable::default()))
+    }
+
+    #[test]
+    fn traces_are_keyed_by_function_and_pc() {
+        let mut cache = TraceCache::new(1 << 20);
+        assert!(cache.insert("f", 9, trace(1)));
+
+        assert!(cache.get("f", 9, &[]).is_some());
+        assert!(cache.get("g", 9, &[]).is_none());
+        assert!(cache.get("f", 4, &[]).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_traces_are_evicted() {
+        let size = trace(10).memory_size();
+        let mut cache = TraceCache::new(2 * size);
+        cache.insert("f", 1, trace(10));
+        cache.insert("g", 1, trace(10));
+        cache.get("f", 1, &[]);
+
+        assert!(cache.insert("h", 1, trace(10)));
+        assert!(cache.contains("f", 1));
+        assert!(!cache.contains("g", 1));
+        assert_eq!(cache.memory_used(), 2 * size);
+    }
+
+    #[test]
+    fn traces_larger_than_the_cache_are_rejected() {
+        let mut cache = TraceCache::new(trace(1).memory_size());
+        assert!(!cache.insert("f", 1, trace(10)));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evict_and_clear() {
+        let mut cache = TraceCache::new(1 << 20);
+        cache.insert("f", 1, trace(1));
+        cache.insert("f", 2, trace(1));
+        cache.insert("g", 1, trace(1));
+
+        assert_eq!(cache.evict("f", 1).len(), 1);
+        assert!(cache.evict("f", 1).is_empty());
+        assert_eq!(cache.len(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.memory_used(), 0);
+    }
+}
@@ -1,75 +1,420 @@
 
-use boolinator::Boolinator;
-use kaktus::PushPop;
+use std::rc::Rc;
 
-use super::{TraceInstruction, Comp, Value, Interpreter, CallFrame, Trace};
+
+use super::{Value, Interpreter, CallFrame, Trace, LoopCall};
+use ir::{self, Ir, Ref, Repr, Snapshot};
+use closures::{Closures, Flow, State};
+use native;
+use validate::TraceExit;
 use recovery::Guard;
-use traits::vec::ConvertingStack;
-use repr::InstrPtr;
+use repr::{InstrPtr, Type};
 
 pub struct Runner<'a, 'b: 'a> {
-    pub trace: &'a [TraceInstruction],
-    pub stack: Vec<Value>,
-    pub locals: Vec<Value>,
+    pub trace: &'a Trace,
     pub interp: &'a mut Interpreter<'b>,
+    // index of a guard, which failed often enough to get a bridge
+    pub hot_exit: Option<usize>,
+    // the trace was left through a loop exit
+    pub left_loop: bool,
+    // where the trace was left, with `--validate`
+    pub exit: Option<TraceExit>,
+    // iterations of the running loop traces, outermost first. The preamble
+    // of a peeled trace counts as an iteration (see `opt/peel.rs`).
+    iterations: Vec<usize>,
+    // locals of all running traces
+    slots: Slots,
+    // register files of finished executions, reused by the next ones.
+    // Inner traces and bridges need their own (see `Runner::execute_any`).
+    registers: Vec<Registers>,
+}
+
+enum Step {
+    // continue in the interpreter
+    Exit(InstrPtr),
+    // continue with the bridge of a failed guard, which starts with the
+    // operand stack of the guard
+    Bridge(Rc<Trace>, Vec<Value>),
+    // end of a bridge, continue at the loop header
+    LoopBack,
+    // an inner trace left its loop, continue with the calling trace
+    LoopExit,
+}
+
+/// Inner loop traces currently executed, innermost first.
+struct Nesting<'n> {
+    call: &'n LoopCall,
+    // operand stack of the calling trace
+    snapshot: &'n Snapshot,
+    trace: &'n Trace,
+    values: &'n Registers,
+    // offset of the locals of the inner trace
+    base: usize,
+    outer: Option<&'n Nesting<'n>>,
 }
 
 impl<'a, 'b> Runner<'a, 'b> {
     pub fn new(interp: &'a mut Interpreter<'b>, trace: &'a Trace) -> Self {
-        // we have to copy over current stack frame from interpreter
+        // we have to copy over current stack frame from interpreter, its
+        // types were checked against the entry guards of the trace
         let mut locals = vec![Value::Null; trace.locals_count];
         {
             let interp_locals = &interp.frames.last().unwrap().locals;
-            for idx in 0..interp_locals.len() {
-                locals[idx] = interp_locals[idx].clone();
-            }
+            locals[..interp_locals.len()].clone_from_slice(interp_locals);
         }
 
         Runner {
-            interp: interp,
-            trace: &trace.trace,
-            stack: Vec::new(),
-            locals: locals,
+            interp,
+            trace,
+            hot_exit: None,
+            left_loop: false,
+            exit: None,
+            iterations: Vec::new(),
+            slots: Slots::new(locals),
+            registers: Vec::new(),
         }
     }
 
     pub fn run(&mut self) -> InstrPtr {
-        use TraceInstruction::*;
+        let trace = self.trace;
+
+        match self.run_loop(trace, None) {
+            Step::Exit(next) => next,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Executes the loop `trace` and its bridges.
+    fn run_loop(&mut self, trace: &Trace, nesting: Option<&Nesting>) -> Step {
+        self.iterations.push(0);
+        let step = self.run_iterations(trace, nesting);
+        self.iterations.pop();
+        step
+    }
+
+    fn run_iterations(&mut self, trace: &Trace, nesting: Option<&Nesting>) -> Step {
+        let base = nesting.map_or(0, |n| n.base);
+
+        loop {
+            let step = match trace.native {
+                Some(ref code) => self.execute_native(trace, code, nesting),
+                None => None,
+            };
+            let step = match step {
+                Some(step) => step,
+                None => self.execute_any(trace, true, &[], nesting),
+            };
+
+            let (bridge, entry) = match step {
+                Step::Bridge(bridge, entry) => (bridge, entry),
+                step => return step,
+            };
+
+            info!("T: entering bridge");
+            // bridges might inline additional frames
+            self.slots.reserve(base + bridge.locals_count);
+
+            match self.execute_any(&bridge, false, &entry, nesting) {
+                Step::LoopBack => self.next_iteration(),
+                step => return step,
+            }
+        }
+    }
+
+    /// Executes `trace` with its closures, if it has been compiled to them.
+    fn execute_any(&mut self,
+                   trace: &Trace,
+                   looping: bool,
+                   entry: &[Value],
+                   nesting: Option<&Nesting>)
+                   -> Step {
+        let mut values = self.take_registers(trace.register_count);
+        let step = match trace.closures {
+            Some(ref closures) => self.execute_closures(trace, closures, looping, entry, &mut values, nesting),
+            None => self.execute(trace, looping, entry, &mut values, nesting),
+        };
+        self.registers.push(values);
+        step
+    }
+
+    /// Register file for `count` registers, reusing the one of a finished
+    /// execution.
+    fn take_registers(&mut self, count: usize) -> Registers {
+        match self.registers.pop() {
+            Some(mut values) => {
+                values.reset(count);
+                values
+            }
+            None => Registers::new(count),
+        }
+    }
+
+    /// Executes a trace (`looping`) or a bridge, which starts with the
+    /// operand stack `entry`.
+    fn execute(&mut self,
+               trace: &Trace,
+               looping: bool,
+               entry: &[Value],
+               values: &mut Registers,
+               nesting: Option<&Nesting>)
+               -> Step {
+        let ir = &trace.trace;
+        let base = nesting.map_or(0, |n| n.base);
+
+        // values of the current iteration, see `regalloc.rs`
+        let regs = &trace.registers;
 
         let mut pc = 0;
         loop {
-            let instr = &self.trace[pc];
-            pc = (pc + 1) % self.trace.len();
+            if pc == ir.len() {
+                if !looping {
+                    return Step::LoopBack;
+                }
+                // values defined by the preamble stay valid
+                pc = trace.loop_start;
+                self.next_iteration();
+            }
 
+            let instr = &ir[pc];
             info!("TEXEC: {:?}", instr);
 
             match *instr {
-                Add        => self.add(),
-                Cmp(how)   => self.cmp(how),
-                Load(idx)  => self.load(idx),
-                Store(idx) => self.store(idx),
-                ArrayGet   => self.array_get(),
-                Const(val) => self.stack.push_from(val),
-                Clone      => {}
-
-                Guard(ref guard) => {
-                    if let Err(recovery) = self.check_guard(guard) {
-                        return recovery;
+                Ir::Const(n) => values.raw[regs[pc]] = n,
+                Ir::ConstBool(b) => values.raw[regs[pc]] = b as usize,
+                Ir::Load(slot) => self.slots.load(base + slot, values, regs[pc], trace.read_only[slot]),
+                Ir::Store(slot, val) => self.slots.store(base + slot, trace, values, val),
+
+                Ir::Add(a, b) => values.raw[regs[pc]] = values.raw[regs[a]] + values.raw[regs[b]],
+                Ir::Overflows(a, b) => {
+                    values.raw[regs[pc]] = values.raw[regs[a]].checked_add(values.raw[regs[b]]).is_none() as usize;
+                }
+                Ir::Cmp(how, left, right) => {
+                    values.raw[regs[pc]] = how.apply(values.raw[regs[left]], values.raw[regs[right]]) as usize;
+                }
+                Ir::ArrayGet(xs, index) => {
+                    values.raw[regs[pc]] = values.array(regs[xs], &self.slots)[values.raw[regs[index]]];
+                }
+                Ir::Len(xs) => values.raw[regs[pc]] = values.array(regs[xs], &self.slots).len(),
+                Ir::NewArray(capacity) => values.set(regs[pc], Value::Array(Vec::with_capacity(capacity))),
+                Ir::Push(xs, val) => {
+                    let mut xs = values.array(regs[xs], &self.slots).to_vec();
+                    xs.push(values.raw[regs[val]]);
+                    values.set(regs[pc], Value::Array(xs));
+                }
+                // see `Registers::value`
+                Ir::Sunk(_) => (),
+
+                Ir::Entry(idx) => values.load(regs[pc], &entry[idx]),
+                Ir::Nop => (),
+
+                Ir::Guard(cond, ref guard, _) => {
+                    if (values.raw[regs[cond]] != 0) != guard.condition {
+                        return self.exit(trace, pc, looping, values, nesting);
                     }
                 }
+                Ir::Exit(..) => return self.exit(trace, pc, looping, values, nesting),
 
-                // these opcodes are not needed for example
-                Array(_) | Push | Print | Len => unimplemented!(),
+                Ir::CallLoop(..) => {
+                    if let Some(step) = self.call_loop(trace, pc, values, nesting) {
+                        return step;
+                    }
+                }
+            }
+
+            pc += 1;
+            // see `opt/peel.rs`
+            if looping && pc == trace.loop_start {
+                self.next_iteration();
             }
         }
     }
 
-    fn check_guard(&mut self, guard: &Guard) -> Result<(), InstrPtr> {
-        let check = self.stack.pop_into::<bool>() == guard.condition;
-        check.ok_or_else(||{
-                self.recover(guard);
-                InstrPtr::new(guard.frame.func.clone(), guard.pc)
+    /// Like `execute`, but calls the closures of `trace` (see `closures`).
+    fn execute_closures(&mut self,
+                        trace: &Trace,
+                        closures: &Closures,
+                        looping: bool,
+                        entry: &[Value],
+                        values: &mut Registers,
+                        nesting: Option<&Nesting>)
+                        -> Step {
+        let ops = &closures.ops;
+        let base = nesting.map_or(0, |n| n.base);
+
+        let mut pc = 0;
+        loop {
+            if pc == ops.len() {
+                if !looping {
+                    return Step::LoopBack;
+                }
+                pc = trace.loop_start;
+                self.next_iteration();
+            }
+
+            let flow = ops[pc](&mut State {
+                values,
+                slots: &mut self.slots,
+                base,
+                entry,
+            });
+
+            match flow {
+                Flow::Next => (),
+                Flow::Exit => return self.exit(trace, pc, looping, values, nesting),
+                Flow::CallLoop => {
+                    if let Some(step) = self.call_loop(trace, pc, values, nesting) {
+                        return step;
+                    }
+                }
+            }
+
+            pc += 1;
+            // see `opt/peel.rs`
+            if looping && pc == trace.loop_start {
+                self.next_iteration();
+            }
+        }
+    }
+
+    /// Runs the inner loop called at `pc`, `None` if it was left and `trace`
+    /// continues.
+    fn call_loop(&mut self, trace: &Trace, pc: usize, values: &Registers, nesting: Option<&Nesting>) -> Option<Step> {
+        let (call, snapshot) = match trace.trace[pc] {
+            Ir::CallLoop(ref call, ref snapshot) => (call, snapshot),
+            _ => panic!("no loop call at {:?}", pc),
+        };
+
+        let inner = Nesting {
+            call,
+            snapshot,
+            trace,
+            values,
+            base: nesting.map_or(0, |n| n.base) + call.offset,
+            outer: nesting,
+        };
+
+        self.slots.reserve(inner.base + call.trace.locals_count);
+
+        match self.run_loop(&call.trace, Some(&inner)) {
+            Step::LoopExit => None,
+            step => Some(step),
+        }
+    }
+
+    /// Executes the machine code of `trace` up to the first failing guard,
+    /// `None` if the locals don't have the types it was compiled for.
+    fn execute_native(&mut self, trace: &Trace, code: &native::Code, nesting: Option<&Nesting>) -> Option<Step> {
+        let base = nesting.map_or(0, |n| n.base);
+        let mut slots = code.unbox(&self.slots, base)?;
+        let mut values = self.take_registers(trace.register_count);
+
+        let pc = unsafe { code.run(&mut values.raw, &mut slots) };
+        info!("TEXEC: native code left at guard {:?}", pc);
+        *self.iterations.last_mut().unwrap() += code.iterations(&slots);
+
+        let snapshot = ir::snapshot(&trace.trace[pc]).unwrap();
+
+        // the code loads arrays as pointers to their slot, which it never
+        // writes (see `native::Code::unbox`)
+        let loaded = snapshot.stack.iter().cloned().chain(snapshot.slots.iter().map(|&(_, val)| val));
+        for val in loaded {
+            if let Ir::Load(slot) = trace.trace[val] {
+                values.places[trace.registers[val]] = match code.slot_type(slot) {
+                    Type::Array => Place::Slot(base + slot),
+                    ty => Place::Raw(ty),
+                };
+            }
+        }
+        code.write_back(&slots, &mut self.slots, base);
+
+        let step = self.exit(trace, pc, true, &values, nesting);
+        self.registers.push(values);
+        Some(step)
+    }
+
+    /// Leaves `trace` through the failed guard (or exit) at `pc`.
+    fn exit(&mut self, trace: &Trace, pc: usize, looping: bool, values: &Registers, nesting: Option<&Nesting>) -> Step {
+        let (guard, snapshot) = match trace.trace[pc] {
+            Ir::Guard(_, ref guard, ref snapshot) |
+            Ir::Exit(ref guard, ref snapshot) => (guard, snapshot),
+            _ => panic!("no guard at {:?}", pc),
+        };
+
+        // stores removed from the trace, whatever continues reads the slots
+        let base = nesting.map_or(0, |n| n.base);
+        for &(slot, val) in &snapshot.slots {
+            self.slots.store(base + slot, trace, values, val);
+        }
+
+        // inner traces continue with the calling trace after their loop
+        if guard.loop_exit && nesting.is_some() {
+            return Step::LoopExit;
+        }
+
+        // only guards of the trace itself get bridges
+        let exit = if looping { Some(pc) } else { None };
+        if let Some(idx) = exit {
+            let threshold = self.interp.config.bridge_threshold;
+            let mut exits = trace.exits.borrow_mut();
+            let exit = exits.entry(idx).or_default();
+            exit.failures += 1;
+
+            if let Some(ref bridge) = exit.bridge {
+                let entry = ir::exit_stack(guard, snapshot).iter().map(|val| values.value(trace, *val, &self.slots)).collect();
+                return Step::Bridge(bridge.clone(), entry);
+            }
+
+            // bridges are only recorded for the trace the interpreter entered
+            let hot = threshold > 0 && exit.failures >= threshold;
+            if hot && !exit.blacklisted && !guard.loop_exit && nesting.is_none() {
+                // the interpreter records the bridge, starting where it would
+                // continue after the guarded instruction
+                self.hot_exit = Some(idx);
+                self.record_exit(guard, Some(idx), nesting);
+                self.recover(ir::exit_stack(guard, snapshot), trace, guard.frames, values, nesting);
+                return Step::Exit(InstrPtr::new(trace.deopt.innermost(guard.frames).clone(), guard.exit_pc));
+            }
+        }
+
+        self.left_loop = guard.loop_exit;
+        self.record_exit(guard, exit, nesting);
+        self.recover(&snapshot.stack, trace, guard.frames, values, nesting);
+        Step::Exit(InstrPtr::new(trace.deopt.innermost(guard.frames).clone(), guard.pc))
+    }
+
+    /// Remembers where the trace was left, for `--validate`.
+    fn record_exit(&mut self, guard: &Guard, idx: Option<usize>, nesting: Option<&Nesting>) {
+        if !self.interp.config.validate {
+            return;
+        }
+
+        let mut calls = Vec::new();
+        let mut outer = nesting;
+        while let Some(nesting) = outer {
+            calls.push(nesting);
+            outer = nesting.outer;
+        }
+
+        // the inner loop is in the innermost frame of its call
+        let mut depth = 0;
+        let inner_loops = calls.iter()
+            .rev()
+            .map(|nesting| {
+                depth += nesting.trace.deopt.frames(nesting.call.frames).len() - 1;
+                (nesting.call.header.clone(), depth)
             })
+            .collect();
+
+        self.exit = Some(TraceExit {
+            idx,
+            guard: guard.clone(),
+            iterations: self.iterations.clone(),
+            inner_loops,
+        });
+    }
+
+    fn next_iteration(&mut self) {
+        *self.iterations.last_mut().unwrap() += 1;
     }
 
     /// Recovery (aka Blackholing)
@@ -85,73 +430,431 @@ impl<'a, 'b> Runner<'a, 'b> {
     ///       the interpreter can gain back control.
     ///       Second, we also have to consider the frame where the loop resides
     ///       in, since state might have also has changed there.
+    ///       The frames are read from the `DeoptTable` of the trace, at the
+    ///       index of the failed guard (`frames`). If the guard belongs to an
+    ///       inner loop trace, the frames of the calling traces are recovered
+    ///       as well, from the tables of their traces at the `CallLoop`.
     ///
     ///     * value stack
-    ///       Also the operand stack has to be recovered.
-    ///       Foremost, the condition, which caused the guard to fail, has to be
-    ///       restored.
-    ///       TODO: Are there other values which might have to be recovered?
-    fn recover(&mut self, guard: &Guard) {
+    ///       Also the operand stack has to be recovered, for all frames
+    ///       above. `stack` lists the values of the failing trace, the
+    ///       snapshots of calling traces the ones below. Arrays whose
+    ///       allocation was sunk are allocated now.
+    ///       The interpreter resuming with a conditional jump expects the
+    ///       condition on top, it is part of the snapshot of the guard
+    ///       (see `Guard::condition_on_stack`). Resuming after the jump, it
+    ///       has to be left out (see `ir::exit_stack`).
+    fn recover(&mut self,
+               stack: &[Ref],
+               trace: &Trace,
+               frames: usize,
+               values: &Registers,
+               nesting: Option<&Nesting>) {
         // remove the last callframe of the Interpreter
         // it gets replaced with our updated version
-        self.interp.frames.pop().unwrap();
+        let loop_frame = self.interp.frames.pop().unwrap();
+        self.slots.box_all();
 
-        // recover callframes
-        let frames = guard.frame.walk().collect::<Vec<_>>();
+        // inline stacks of the calling traces and the guard, outermost first,
+        // with the offset of their locals
+        let mut levels = vec![(trace, frames, nesting.map_or(0, |n| n.base))];
+        let mut outer = nesting;
+        while let Some(nesting) = outer {
+            levels.push((nesting.trace, nesting.call.frames, nesting.base - nesting.call.offset));
+            outer = nesting.outer;
+        }
+        levels.reverse();
 
         // since callframes depend on each other, we start with the one which
-        // was created first (least-recent frame) `.rev()` ensures that
-        for frame_info in frames.iter().rev() {
-            // 1. create a new callframe to push
-            let mut frame = CallFrame::for_fn(
-                &frame_info.func,
-                frame_info.back_ref.clone());
+        // was created first (least-recent frame). The loop frame of the
+        // entered trace returns to where the replaced frame returned to.
+        let mut back_ref = loop_frame.back_ref;
+        for (level, &(trace, frames, base)) in levels.iter().enumerate() {
+            let table = &trace.deopt;
+            let frames = table.frames(frames);
+            let mut offset = base;
+
+            for (idx, frame) in frames.iter().enumerate() {
+                offset += frame.delta as usize;
+                if idx > 0 {
+                    back_ref = InstrPtr::new(table.func(&frames[idx - 1]).clone(), frame.return_pc as usize);
+                }
+
+                // the innermost frame of a call is the first one of the next
+                // level, returning to where the frame of the call returns to
+                if level + 1 < levels.len() && idx + 1 == frames.len() {
+                    break;
+                }
 
-            // 2. fill it up with locals
-            for idx in 0..frame.locals.len() {
-                frame.locals[idx] = self.locals[frame_info.offset + idx].clone();
+                // 1. create a new callframe to push
+                let mut callframe = CallFrame::for_fn(table.func(frame), back_ref.clone());
+
+                // 2. fill it up with locals
+                for (idx, local) in callframe.locals.iter_mut().enumerate() {
+                    *local = self.slots.boxed[offset + idx].clone();
+                }
+
+                // 3. add frame to interpreter callframes
+                self.interp.frames.push(callframe);
             }
+        }
 
-            // 3. add frame to interpreter callframes
-            self.interp.frames.push(frame);
+        // recover value stack, starting with the outermost trace
+        let mut stacks = vec![(stack, trace, values)];
+        let mut outer = nesting;
+        while let Some(nesting) = outer {
+            stacks.push((&nesting.snapshot.stack[..], nesting.trace, nesting.values));
+            outer = nesting.outer;
         }
 
-        // recover value stack
-        self.interp.stack.push_from(!guard.condition);
+        for &(stack, trace, values) in stacks.iter().rev() {
+            for val in stack {
+                self.interp.stack.push(values.value(trace, *val, &self.slots));
+            }
+        }
     }
 }
 
-// normal interpreter functions
-impl<'a, 'b> Runner<'a, 'b> {
-    fn add(&mut self) {
-        let (a, b) = self.stack.pop_2_into::<usize>();
-        self.stack.push_from(a + b)
+/// Values of a trace, numbers and bools are kept unboxed in `raw` (bools as
+/// `0` or `1`), arrays in `boxed`. For values whose type is only known at
+/// runtime (see `ir::repr`), `places` tells where they are.
+pub struct Registers {
+    pub raw: Vec<usize>,
+    boxed: Vec<Value>,
+    places: Vec<Place>,
+}
+
+/// Where a register with a `Repr::Boxed` value keeps it.
+#[derive(Debug, Clone, Copy)]
+enum Place {
+    // number, bool or null in `raw`
+    Raw(Type),
+    Boxed,
+    // array in a read-only slot, see `Slots::load`
+    Slot(usize),
+}
+
+impl Registers {
+    pub fn new(count: usize) -> Self {
+        Registers {
+            raw: vec![0; count],
+            boxed: vec![Value::Null; count],
+            places: vec![Place::Boxed; count],
+        }
     }
 
-    fn cmp(&mut self, how: Comp) {
-        let (left, right) = self.stack.pop_2_into::<usize>();
+    /// Empties the registers, keeping their memory.
+    fn reset(&mut self, count: usize) {
+        self.raw.clear();
+        self.raw.resize(count, 0);
+        self.boxed.clear();
+        self.boxed.resize(count, Value::Null);
+        self.places.clear();
+        self.places.resize(count, Place::Boxed);
+    }
 
-        let b = match how {
-            Comp::Lt => left < right,
-            Comp::Le => left <= right,
-            _ => panic!("TODO"),
+    /// Unboxes `value` into `register`, only arrays are copied.
+    pub fn load(&mut self, register: usize, value: &Value) {
+        self.places[register] = match *value {
+            Value::Null => Place::Raw(Type::Null),
+            Value::Bool(b) => {
+                self.raw[register] = b as usize;
+                Place::Raw(Type::Bool)
+            }
+            Value::Usize(n) => {
+                self.raw[register] = n;
+                Place::Raw(Type::Usize)
+            }
+            Value::Array(_) => {
+                self.boxed[register] = value.clone();
+                Place::Boxed
+            }
         };
+    }
+
+    /// Sets `register` to a new array.
+    pub fn set(&mut self, register: usize, array: Value) {
+        self.boxed[register] = array;
+        self.places[register] = Place::Boxed;
+    }
+
+    /// The array in `register`.
+    pub fn array<'v>(&'v self, register: usize, slots: &'v Slots) -> &'v [usize] {
+        match self.places[register] {
+            Place::Slot(slot) => slots.boxed[slot].as_array(),
+            _ => self.boxed[register].as_array(),
+        }
+    }
+
+    /// Boxed value of instruction `val` of `trace`, allocates sunk arrays.
+    fn value(&self, trace: &Trace, val: Ref, slots: &Slots) -> Value {
+        let register = trace.registers[val];
+        match ir::repr(&trace.trace[val]) {
+            Repr::Usize => Value::Usize(self.raw[register]),
+            Repr::Bool => Value::Bool(self.raw[register] != 0),
+            Repr::Boxed => self.boxed_value(register, slots),
+            Repr::Sunk(elements) => {
+                Value::Array(elements.iter().map(|element| self.raw[trace.registers[*element]]).collect())
+            }
+        }
+    }
+
+    fn boxed_value(&self, register: usize, slots: &Slots) -> Value {
+        match self.places[register] {
+            Place::Raw(ty) => boxed(ty, self.raw[register]),
+            Place::Boxed => self.boxed[register].clone(),
+            Place::Slot(slot) => slots.boxed[slot].clone(),
+        }
+    }
+}
+
+/// Locals of the running traces, indexed by absolute slot.
+///
+/// Numbers and bools stored by a trace stay unboxed in `raw`, until the
+/// interpreter takes over (see `Runner::recover`). Everything else, and the
+/// locals the traces didn't store, are in `boxed`.
+pub struct Slots {
+    boxed: Vec<Value>,
+    raw: Vec<usize>,
+    // type of the unboxed value in `raw`, `None` if `boxed` is current
+    unboxed: Vec<Option<Type>>,
+}
+
+impl Slots {
+    fn new(locals: Vec<Value>) -> Self {
+        let count = locals.len();
+        Slots {
+            boxed: locals,
+            raw: vec![0; count],
+            unboxed: vec![None; count],
+        }
+    }
+
+    fn reserve(&mut self, count: usize) {
+        if self.boxed.len() < count {
+            self.boxed.resize(count, Value::Null);
+            self.raw.resize(count, 0);
+            self.unboxed.resize(count, None);
+        }
+    }
+
+    /// Type of the value in `slot`.
+    pub fn ty(&self, slot: usize) -> Type {
+        self.unboxed[slot].unwrap_or_else(|| self.boxed[slot].ty())
+    }
+
+    /// Unboxed value of `slot`, `None` for arrays.
+    pub fn raw(&self, slot: usize) -> Option<usize> {
+        if self.unboxed[slot].is_some() {
+            return Some(self.raw[slot]);
+        }
+        match self.boxed[slot] {
+            Value::Null => Some(0),
+            Value::Bool(b) => Some(b as usize),
+            Value::Usize(n) => Some(n),
+            Value::Array(_) => None,
+        }
+    }
+
+    /// The boxed array in `slot`.
+    pub fn array(&self, slot: usize) -> &Value {
+        &self.boxed[slot]
+    }
+
+    /// Loads `slot` into `register`. Arrays in `read_only` slots (see
+    /// `Trace::read_only`) stay where they are, instead of being copied.
+    pub fn load(&self, slot: usize, values: &mut Registers, register: usize, read_only: bool) {
+        match self.unboxed[slot] {
+            Some(ty) => {
+                values.raw[register] = self.raw[slot];
+                values.places[register] = Place::Raw(ty);
+            }
+            None if read_only && self.boxed[slot].ty() == Type::Array => values.places[register] = Place::Slot(slot),
+            None => values.load(register, &self.boxed[slot]),
+        }
+    }
 
-        self.stack.push_from(b);
+    /// Stores the value of instruction `val` of `trace`.
+    fn store(&mut self, slot: usize, trace: &Trace, values: &Registers, val: Ref) {
+        let register = trace.registers[val];
+        match ir::repr(&trace.trace[val]) {
+            Repr::Usize => self.store_raw(slot, Type::Usize, values.raw[register]),
+            Repr::Bool => self.store_raw(slot, Type::Bool, values.raw[register]),
+            Repr::Boxed => self.store_register(slot, values, register),
+            Repr::Sunk(_) => {
+                let array = values.value(trace, val, self);
+                self.store_array(slot, array);
+            }
+        }
     }
 
-    fn load(&mut self, idx: usize) {
-        let val = self.locals[idx].clone();
-        self.stack.push(val);
+    pub fn store_raw(&mut self, slot: usize, ty: Type, raw: usize) {
+        self.raw[slot] = raw;
+        self.unboxed[slot] = Some(ty);
+    }
+
+    /// Stores the `Repr::Boxed` value in `register`.
+    pub fn store_register(&mut self, slot: usize, values: &Registers, register: usize) {
+        match values.places[register] {
+            Place::Raw(ty) => self.store_raw(slot, ty, values.raw[register]),
+            _ => {
+                let array = values.boxed_value(register, self);
+                self.store_array(slot, array);
+            }
+        }
     }
 
-    fn store(&mut self, idx: usize) {
-        self.locals[idx] = self.stack.pop_into();
+    pub fn store_array(&mut self, slot: usize, array: Value) {
+        self.boxed[slot] = array;
+        self.unboxed[slot] = None;
     }
 
-    fn array_get(&mut self) {
-        let index: usize = self.stack.pop_into();
-        let xs: Vec<usize> = self.stack.pop_into();
-        self.stack.push_from(xs[index]);
+    /// Boxes the values stored by the traces.
+    fn box_all(&mut self) {
+        for (slot, ty) in self.unboxed.iter_mut().enumerate() {
+            if let Some(ty) = ty.take() {
+                self.boxed[slot] = boxed(ty, self.raw[slot]);
+            }
+        }
+    }
+}
+
+fn boxed(ty: Type, raw: usize) -> Value {
+    match ty {
+        Type::Null => Value::Null,
+        Type::Bool => Value::Bool(raw != 0),
+        Type::Usize => Value::Usize(raw),
+        // arrays are never unboxed
+        Type::Array => unreachable!(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use repr::{InstrPtr, Type, Value};
+    use testing;
+    use Interpreter;
+
+    use super::{Place, Registers, Slots};
+
+    /// Runs the trace of example program `name` from the start of its loop,
+    /// returns where the interpreter continues, whether the trace left the
+    /// loop and the locals of the loop frame afterwards.
+    fn run_loop(name: &str, flags: &[&str], locals: &[Value]) -> (InstrPtr, InstrPtr, bool, Vec<Value>) {
+        let (module, header, trace) = testing::trace_of(name, flags);
+        let mut interpreter = Interpreter::with_config(&module, testing::config(flags));
+        let (next, left_loop) = testing::run_trace(&mut interpreter, &header, &trace, locals);
+
+        assert_eq!(interpreter.frames.len(), 1);
+        let locals = interpreter.frames.pop().unwrap().locals;
+        (header, next, left_loop, locals)
+    }
+
+    fn usizes(values: &[usize]) -> Vec<Value> {
+        values.iter().map(|n| Value::Usize(*n)).collect()
+    }
+
+    #[test]
+    fn loop_condition_exits_after_the_loop() {
+        // sum, i: adds up `i..10`
+        let (header, next, left_loop, locals) = run_loop("sum", &[], &usizes(&[0, 3]));
+
+        assert!(left_loop);
+        assert_eq!(next.pc, header.func.loop_end(header.pc).unwrap() + 1);
+        assert_eq!(locals[..2], usizes(&[42, 10])[..]);
+    }
+
+    #[test]
+    fn break_exits_after_the_loop() {
+        // i, sum: adds up `i..=5`, then `break`s
+        let (header, next, left_loop, locals) = run_loop("break_loop", &[], &usizes(&[2, 100]));
+
+        assert!(left_loop);
+        assert_eq!(next.pc, header.func.loop_end(header.pc).unwrap() + 1);
+        assert_eq!(locals, usizes(&[6, 114]));
+    }
+
+    #[test]
+    fn loop_exits_are_the_same_without_bytecode_rewriting() {
+        let flags = ["--no-peephole", "--no-superinstructions"];
+        for &(name, ref entry, ref exit) in &[("sum", usizes(&[0, 3]), usizes(&[42, 10])),
+                                            ("break_loop", usizes(&[2, 100]), usizes(&[6, 114]))] {
+            let (header, next, left_loop, locals) = run_loop(name, &flags, entry);
+
+            assert!(left_loop, "{}", name);
+            assert_eq!(next.pc, header.func.loop_end(header.pc).unwrap() + 1, "{}", name);
+            assert_eq!(locals[..exit.len()], exit[..], "{}", name);
+        }
+    }
+
+    #[test]
+    fn stored_numbers_stay_unboxed_until_the_trace_is_left() {
+        let mut slots = Slots::new(usizes(&[1, 2]));
+        slots.store_raw(0, Type::Bool, 1);
+
+        assert_eq!(slots.ty(0), Type::Bool);
+        assert_eq!(slots.raw(0), Some(1));
+        assert_eq!(slots.boxed, usizes(&[1, 2]));
+
+        slots.box_all();
+        assert_eq!(slots.boxed, vec![Value::Bool(true), Value::Usize(2)]);
+        assert_eq!(slots.unboxed, vec![None, None]);
+    }
+
+    #[test]
+    fn read_only_arrays_are_loaded_in_place() {
+        let slots = Slots::new(vec![Value::Array(vec![4, 2])]);
+        let mut values = Registers::new(3);
+
+        slots.load(0, &mut values, 1, true);
+        slots.load(0, &mut values, 2, false);
+        assert!(matches!(values.places[1], Place::Slot(0)));
+        assert!(matches!(values.places[2], Place::Boxed));
+        assert_eq!(values.array(1, &slots), [4, 2]);
+        assert_eq!(values.array(2, &slots), [4, 2]);
+    }
+
+    #[test]
+    fn reset_registers_are_empty() {
+        let mut values = Registers::new(2);
+        values.load(1, &Value::Array(vec![1]));
+        values.raw[0] = 7;
+
+        values.reset(3);
+        assert_eq!(values.raw, vec![0; 3]);
+        assert_eq!(values.boxed, vec![Value::Null; 3]);
+        assert!(values.places.iter().all(|place| matches!(*place, Place::Boxed)));
+    }
+
+    #[test]
+    fn min_list_locals_are_boxed_when_leaving() {
+        let (module, header, trace) = testing::trace_of("min_list", &[]);
+        assert!(trace.read_only[0]);
+
+        // the list is only read, the guard on the new minimum fails at 4,
+        // in the inlined frame of `min`
+        let mut interpreter = Interpreter::with_config(&module, testing::config(&[]));
+        let xs = Value::Array(vec![9, 3, 4]);
+        let (_, left_loop) = testing::run_trace(&mut interpreter, &header, &trace,
+                                                &[xs.clone(), Value::Usize(9), Value::Usize(3), Value::Usize(0)]);
+
+        assert!(!left_loop);
+        assert_eq!(interpreter.frames.len(), 2);
+        assert_eq!(interpreter.frames[0].locals, vec![xs, Value::Usize(3), Value::Usize(3), Value::Usize(2)]);
+        assert_eq!(interpreter.frames[1].locals, usizes(&[3, 4]));
+    }
+
+    #[test]
+    fn guards_in_inlined_frames_recover_the_stack_of_the_loop_frame() {
+        // total, i: `pick(5)` takes the other branch, with `total` pending
+        let entry = usizes(&[4, 5]);
+        let expected = (String::from("pick"), 3, false, vec![usizes(&[4, 5]), usizes(&[5])],
+                        vec![Value::Usize(4), Value::Bool(false)]);
+
+        for flags in &[&[][..], &["--closures"], &["--native"]] {
+            let flags = flags.iter().cloned().chain(Some("--bridge-threshold=0")).collect::<Vec<_>>();
+            assert_eq!(testing::trace_exit("pending_call", &flags, &entry), expected, "{:?}", flags);
+        }
     }
 }
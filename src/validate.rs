@@ -0,0 +1,231 @@
+//! Shadow execution of traces
+//!
+//! With `--validate`, the state the interpreter continues with after a trace
+//! has been left is compared against a reference: an interpreter without
+//! JIT, which starts with the state the trace was entered with. It executes
+//! the loop as many times as the trace (and the traces of inner loops) did,
+//! up to the instruction the interpreter continues with.
+//!
+//! The first difference in frames, locals or the operand stack fails the
+//! run, reporting the trace, the guard it was left through and the values.
+
+use recovery::Guard;
+use repr::InstrPtr;
+
+use super::Interpreter;
+
+
+/// Where a trace was left, see `Runner::exit`.
+pub struct TraceExit {
+    // index of the guard in its trace, `None` for guards of bridges
+    pub idx: Option<usize>,
+    pub guard: Guard,
+    // iterations completed by the entered trace and the running inner traces,
+    // outermost first
+    pub iterations: Vec<usize>,
+    // header of the loop of every running inner trace, with the depth of its
+    // frame relative to the loop frame of the entered trace
+    pub inner_loops: Vec<(InstrPtr, usize)>,
+}
+
+/// The reference for a trace entered at the loop `header`.
+pub struct Shadow<'a> {
+    reference: Interpreter<'a>,
+    header: InstrPtr,
+}
+
+impl<'a> Shadow<'a> {
+    pub fn new(interp: &Interpreter<'a>, header: &InstrPtr) -> Self {
+        let mut config = interp.config.clone();
+        config.jit = false;
+        config.quiet = true;
+
+        let mut reference = Interpreter::with_config(interp.module, config);
+        reference.frames = interp.frames.clone();
+        reference.stack = interp.stack.clone();
+
+        Shadow {
+            reference,
+            header: header.clone(),
+        }
+    }
+
+    /// Compares `interp`, which continues with `next` after leaving the trace
+    /// through `exit`, against the reference. Panics at the first difference.
+    pub fn check(mut self, interp: &Interpreter, next: &InstrPtr, exit: &TraceExit) {
+        let result = self.run_to(interp, next, exit).and_then(|()| compare(&self.reference, interp));
+
+        if let Err(divergence) = result {
+            let guard = match exit.idx {
+                Some(idx) => format!("guard {:04} {:?}", idx, exit.guard),
+                None => format!("guard {:?} of a bridge", exit.guard),
+            };
+            panic!("validation of trace @{} failed after {:?} iterations, left through {}: {}",
+                   at(&self.header), exit.iterations, guard, divergence);
+        }
+    }
+
+    /// Executes the reference up to `next`, in the same iteration of every
+    /// loop.
+    fn run_to(&mut self, interp: &Interpreter, next: &InstrPtr, exit: &TraceExit) -> Result<(), String> {
+        let depth = self.reference.frames.len();
+
+        // header, depth of its frame and number of times it is reached
+        let mut loops = vec![(self.header.clone(), depth, exit.iterations[0])];
+        for (level, &(ref header, offset)) in exit.inner_loops.iter().enumerate() {
+            // inner loops are reached once before their first iteration
+            loops.push((header.clone(), depth + offset, exit.iterations[level + 1] + 1));
+        }
+        let expected = loops.iter().map(|&(_, _, count)| count).collect::<Vec<_>>();
+        let mut reached = vec![0; loops.len()];
+
+        // the trace starts behind the header
+        let mut instr = self.header.next();
+        loop {
+            let frames = self.reference.frames.len();
+            let level = loops.iter().position(|&(ref header, depth, _)| same(&instr, header) && frames == depth);
+            if let Some(level) = level {
+                reached[level] += 1;
+                for count in &mut reached[level + 1..] {
+                    *count = 0;
+                }
+
+                if reached[..level] == expected[..level] && reached[level] > expected[level] {
+                    return Err(format!("the reference reached the loop @{} more than {} times, but not @{}",
+                                       at(&instr), expected[level], at(next)));
+                }
+            }
+
+            if reached == expected && same(&instr, next) && frames == interp.frames.len() {
+                return Ok(());
+            }
+
+            instr = match self.reference.step(&instr) {
+                Ok(Some(instr)) => instr,
+                Ok(None) => return Err(format!("the reference ended before @{}", at(next))),
+                Err(error) => return Err(format!("the reference failed with \"{}\" before @{}", error, at(next))),
+            };
+        }
+    }
+}
+
+fn compare(reference: &Interpreter, interp: &Interpreter) -> Result<(), String> {
+    if reference.frames.len() != interp.frames.len() {
+        return Err(format!("{} frames, expected {}", interp.frames.len(), reference.frames.len()));
+    }
+
+    for (depth, (expected, got)) in reference.frames.iter().zip(&interp.frames).enumerate() {
+        if !same(&expected.back_ref, &got.back_ref) {
+            return Err(format!("frame {} returns to @{}, expected @{}",
+                               depth, at(&got.back_ref), at(&expected.back_ref)));
+        }
+        if expected.locals != got.locals {
+            return Err(format!("locals of frame {} are {:?}, expected {:?}", depth, got.locals, expected.locals));
+        }
+    }
+
+    if reference.stack != interp.stack {
+        return Err(format!("stack is {:?}, expected {:?}", interp.stack, reference.stack));
+    }
+
+    Ok(())
+}
+
+fn same(a: &InstrPtr, b: &InstrPtr) -> bool {
+    a.func.name == b.func.name && a.pc == b.pc
+}
+
+fn at(instr: &InstrPtr) -> String {
+    format!("{}[{}]", instr.func.name, instr.pc)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use repr::{CallFrame, InstrPtr, Value};
+    use testing;
+    use tracerunner::Runner;
+    use {Interpreter, Module, Trace};
+
+    use super::{compare, Shadow, TraceExit};
+
+    fn usizes(values: &[usize]) -> Vec<Value> {
+        values.iter().map(|n| Value::Usize(*n)).collect()
+    }
+
+    /// Enters `trace` of the loop at `header` with `locals`, returns the
+    /// interpreter after leaving it, its reference, where it continues and
+    /// where the trace was left.
+    fn left_trace<'a>(module: &'a Module,
+                      header: &InstrPtr,
+                      trace: &Trace,
+                      locals: &[Value])
+                      -> (Interpreter<'a>, Shadow<'a>, InstrPtr, TraceExit) {
+        let mut interpreter = Interpreter::with_config(module, testing::config(&["--validate"]));
+        let mut frame = CallFrame::for_fn(&header.func, InstrPtr::new(header.func.clone(), 0));
+        frame.locals[..locals.len()].clone_from_slice(locals);
+        interpreter.frames.push(frame);
+
+        let shadow = Shadow::new(&interpreter, header);
+        let (next, exit) = {
+            let mut runner = Runner::new(&mut interpreter, trace);
+            let next = runner.run();
+            (next, runner.exit.take().unwrap())
+        };
+        (interpreter, shadow, next, exit)
+    }
+
+    /// The difference `corrupt` makes to the state after leaving the trace of
+    /// `sum`, once the reference caught up.
+    fn divergence(corrupt: fn(&mut Interpreter)) -> Result<(), String> {
+        let (module, header, trace) = testing::trace_of("sum", &[]);
+        let (mut interpreter, mut shadow, next, exit) = left_trace(&module, &header, &trace, &usizes(&[0, 3]));
+
+        shadow.run_to(&interpreter, &next, &exit)?;
+        corrupt(&mut interpreter);
+        compare(&shadow.reference, &interpreter)
+    }
+
+    #[test]
+    fn traces_match_their_reference() {
+        assert_eq!(divergence(|_| ()), Ok(()));
+
+        // leaving through guards in inlined frames, with and without bridges
+        for flags in &[&["--validate", "--bridge-threshold=0"][..], &["--validate", "--bridge-threshold=1"]] {
+            testing::trace_exit("pending_call", flags, &usizes(&[4, 5]));
+            testing::trace_exit("min_list", flags, &[Value::Array(vec![9, 3, 4]), Value::Usize(9),
+                                                     Value::Usize(3), Value::Usize(0)]);
+        }
+    }
+
+    #[test]
+    fn divergent_locals_are_reported() {
+        let report = divergence(|interpreter| interpreter.frames[0].locals[0] = Value::Usize(41));
+        assert_eq!(report.unwrap_err(),
+                   "locals of frame 0 are [Usize(41), Usize(10), Null], expected [Usize(42), Usize(10), Null]");
+    }
+
+    #[test]
+    fn divergent_stacks_are_reported() {
+        let report = divergence(|interpreter| interpreter.stack.push(Value::Bool(true)));
+        assert_eq!(report.unwrap_err(), "stack is [Bool(true)], expected []");
+    }
+
+    #[test]
+    fn missing_frames_are_reported() {
+        let report = divergence(|interpreter| {
+            interpreter.frames.pop();
+        });
+        assert_eq!(report.unwrap_err(), "0 frames, expected 1");
+    }
+
+    #[test]
+    #[should_panic(expected = "validation of trace @main[")]
+    fn divergences_fail_the_run() {
+        let (module, header, trace) = testing::trace_of("sum", &[]);
+        let (mut interpreter, shadow, next, exit) = left_trace(&module, &header, &trace, &usizes(&[0, 3]));
+
+        interpreter.frames[0].locals[1] = Value::Usize(9);
+        shadow.check(&interpreter, &next, &exit);
+    }
+}